@@ -1,49 +1,95 @@
-use crate::utils::bin_dir;
+use crate::commands::list::list_installed_versions;
+use crate::internal::agent_config::{config_path, AgentConfig};
+use crate::internal::token::{self, TOKEN_ENV_VAR};
+use crate::utils;
 use anyhow::{Context, Result};
 use dialoguer::Password;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, IsTerminal, Read};
+use std::path::Path;
 
-pub fn run(version: &str) -> Result<()> {
-    let config_path = get_config_path(version)?;
+/// Where `bap auth` puts the token it's given.
+pub struct Targets<'a> {
+    pub version: Option<&'a str>,
+    /// Save it as the global token that every version falls back on.
+    pub global: bool,
+    /// Write it into the config file of every installed version.
+    pub all: bool,
+    /// Save it under a name, for directories to pick.
+    pub name: Option<&'a str>,
+}
 
-    let token = Password::new()
-        .with_prompt("Enter the agent token")
-        .interact()?;
+pub fn run(targets: Targets, token_stdin: bool, token_file: Option<&Path>) -> Result<()> {
+    // Check the versions before asking for the token, so nobody types it in
+    // only to find it had nowhere to go
+    let mut configs = Vec::new();
+    if targets.all {
+        for version in list_installed_versions().unwrap_or_default() {
+            match AgentConfig::load_if_exists(&config_path(&version))? {
+                Some(config) => configs.push((version, config)),
+                None => println!("Skipping {}, which has no configuration file", version),
+            }
+        }
+        if configs.is_empty() && !targets.global {
+            anyhow::bail!("No installed Buildkite agent versions have a configuration file.");
+        }
+    } else if let Some(name) = targets.name {
+        token::named_token_path(name)?;
+    } else if let Some(version) = targets.version {
+        configs.push((version.to_string(), AgentConfig::for_version(version)?));
+    }
 
-    update_config_file(&config_path, &token)?;
+    let token = read_token(token_stdin, token_file)?;
 
-    println!("Agent token updated successfully for version {}", version);
-    Ok(())
-}
-
-fn get_config_path(version: &str) -> Result<PathBuf> {
-    let path = bin_dir().join(version).join("buildkite-agent.cfg");
-    if !path.exists() {
-        anyhow::bail!(
-            "Configuration file not found for version {}. Is this version installed?",
-            version
+    if targets.global {
+        token::save_global(&token)?;
+        println!(
+            "Global agent token saved to {}",
+            utils::token_file().display()
         );
     }
-    Ok(path)
-}
 
-fn update_config_file(path: &PathBuf, token: &str) -> Result<()> {
-    let content = fs::read_to_string(path).context("Failed to read configuration file")?;
+    if let Some(name) = targets.name {
+        token::save_named(name, &token)?;
+        println!("Agent token saved as {}", name);
+    }
 
-    let updated_content = content
-        .lines()
-        .map(|line| {
-            if line.trim_start().starts_with("token=") {
-                format!("token=\"{}\"", token)
-            } else {
-                line.to_string()
-            }
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
+    for (version, config) in &mut configs {
+        config.set("token", token.as_str());
+        config.save()?;
+        println!("Agent token updated successfully for version {}", version);
+    }
+    Ok(())
+}
 
-    fs::write(path, updated_content).context("Failed to write updated configuration file")?;
+/// Gets the token from standard input, a file, `BUILDKITE_AGENT_TOKEN` or, as
+/// a last resort, by prompting for it.
+fn read_token(token_stdin: bool, token_file: Option<&Path>) -> Result<String> {
+    let token = if token_stdin {
+        let mut token = String::new();
+        io::stdin()
+            .read_to_string(&mut token)
+            .context("Failed to read the token from standard input")?;
+        token
+    } else if let Some(path) = token_file {
+        fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the token from {}", path.display()))?
+    } else if let Some(token) = std::env::var(TOKEN_ENV_VAR).ok().filter(|t| !t.is_empty()) {
+        token
+    } else if io::stdin().is_terminal() {
+        Password::new()
+            .with_prompt("Enter the agent token")
+            .interact()?
+    } else {
+        anyhow::bail!(
+            "No agent token given and there's no terminal to prompt for one. Pass it with --token-stdin or --token-file <path>, or set {}.",
+            TOKEN_ENV_VAR
+        );
+    };
 
-    Ok(())
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        anyhow::bail!("The agent token is empty");
+    }
+    Ok(token)
 }
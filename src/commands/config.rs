@@ -0,0 +1,103 @@
+use crate::commands::auth;
+use crate::commands::run::{self, Layers};
+use crate::config::{self, Config};
+use crate::internal::agent_config::{config_path, is_valid_key, AgentConfig};
+use crate::internal::preflight;
+use anyhow::{Context, Result};
+use dialoguer::Confirm;
+use std::io::{self, IsTerminal};
+
+pub fn get(version: &str, key: &str) -> Result<()> {
+    let config = AgentConfig::for_version(version)?;
+    match config.get(key) {
+        Some(value) => println!("{}", value),
+        None => anyhow::bail!("{} isn't set for version {}", key, version),
+    }
+    Ok(())
+}
+
+pub fn set(version: &str, key: &str, value: &str) -> Result<()> {
+    if !is_valid_key(key) {
+        anyhow::bail!(
+            "{} isn't a valid setting name, use lowercase letters, digits and dashes like build-path",
+            key
+        );
+    }
+
+    let mut config = AgentConfig::for_version(version)?;
+    config.set(key, value);
+    config.save()?;
+    println!("Set {} for version {}", key, version);
+    Ok(())
+}
+
+pub fn unset(version: &str, key: &str) -> Result<()> {
+    let mut config = AgentConfig::for_version(version)?;
+    if !config.unset(key) {
+        println!("{} isn't set for version {}", key, version);
+        return Ok(());
+    }
+    config.save()?;
+    println!("Unset {} for version {}", key, version);
+    Ok(())
+}
+
+/// Checks a version's config the way `bap run` would in this directory, and
+/// returns whether it was all fine.
+pub fn check(version: Option<&str>) -> Result<bool> {
+    let version = match version {
+        Some(version) => version.to_string(),
+        None => config::get_version()?
+            .context("No version specified and no local or default version set.")?,
+    };
+
+    // Fail on a version that isn't installed, rather than finding no problems
+    AgentConfig::for_version(&version)?;
+
+    let settings = Layers::load(None, None)?.settings(&Config::load()?, &version);
+    let clean = run::preflight(&version, &settings, true)?;
+    if clean {
+        println!("No problems found in the config for version {}", version);
+    }
+    Ok(clean)
+}
+
+/// Checks a version's config file before it's run, printing anything wrong
+/// with it, and returns whether it was all fine. A missing token is the one
+/// problem that stops the agent starting, so bap offers to set one there and
+/// then if it can, and `may_prompt` allows.
+pub fn preflight(version: &str, token_elsewhere: bool, may_prompt: bool) -> Result<bool> {
+    let Some(config) = AgentConfig::load_if_exists(&config_path(version))? else {
+        return Ok(true);
+    };
+    let report = preflight::check(&config, token_elsewhere)?;
+    let path = config.path().display();
+
+    for warning in &report.warnings {
+        println!("Warning: {}: {}", path, warning);
+    }
+
+    if let Some(reason) = &report.missing_token {
+        if !may_prompt || !io::stdin().is_terminal() {
+            anyhow::bail!("{}: {}. Set one with `bap auth {}`", path, reason, version);
+        }
+
+        println!("{}: {}", path, reason);
+        let set_token = Confirm::new()
+            .with_prompt(format!("Set the agent token for version {} now?", version))
+            .default(true)
+            .interact()?;
+        if !set_token {
+            anyhow::bail!("The agent can't start without a token");
+        }
+        let targets = auth::Targets {
+            version: Some(version),
+            global: false,
+            all: false,
+            name: None,
+        };
+        auth::run(targets, false, None)?;
+    }
+
+    Ok(report.is_clean())
+}
@@ -0,0 +1,392 @@
+use crate::commands::run::{self, Agent, RunArgs};
+use crate::commands::start;
+use crate::config::Config;
+use crate::internal::control::{default_socket_path, AgentStatus, Request};
+use crate::internal::events::{Event, EventKind};
+use crate::internal::log_file::daemon_log_path;
+use crate::internal::metrics;
+use crate::internal::notify;
+use crate::internal::output::{OutputMode, RecentLines};
+use crate::internal::signals::{is_stop_signal, signal_name, ForwardedSignals};
+use crate::utils::{bin_dir, run_dir};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use clap::Parser;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, Notify};
+
+/// How many lines of output the daemon keeps in memory for each agent.
+const RECENT_LINES: usize = 1000;
+
+/// Lets a `start` request carry `bap run` arguments.
+#[derive(Parser)]
+#[command(name = "run")]
+struct RunCommand {
+    #[command(flatten)]
+    args: RunArgs,
+}
+
+struct ManagedAgent {
+    status: AgentStatus,
+    /// The extra `bap run` arguments it was started with, to restart it with.
+    args: Vec<String>,
+    /// Start it again once it has stopped.
+    restart: bool,
+    signals: broadcast::Sender<i32>,
+    recent: Arc<RecentLines>,
+}
+
+/// The background supervisor behind `bap daemon`. It runs agents the same way
+/// `bap run` does and lets other tools manage them over a Unix socket.
+struct Daemon {
+    agents: Mutex<BTreeMap<String, ManagedAgent>>,
+    events: broadcast::Sender<Event>,
+    next_id: AtomicU64,
+    shutting_down: Mutex<bool>,
+    agent_exited: Notify,
+}
+
+pub async fn run(
+    socket: Option<PathBuf>,
+    metrics_addr: Option<SocketAddr>,
+    detach: bool,
+) -> Result<()> {
+    let socket = socket.unwrap_or_else(default_socket_path);
+
+    if detach {
+        fs::create_dir_all(run_dir())?;
+        let log = run_dir().join("daemon.log");
+        let mut args: Vec<OsString> = vec!["daemon".into(), "--socket".into()];
+        args.push(socket.clone().into());
+        if let Some(addr) = metrics_addr {
+            args.push("--metrics-addr".into());
+            args.push(addr.to_string().into());
+        }
+        let child = start::spawn_detached(&args, &log)?;
+        println!(
+            "Started the bap daemon in the background (pid {})",
+            child.id()
+        );
+        println!("Socket: {}", socket.display());
+        println!("Logs: {}", log.display());
+        return Ok(());
+    }
+
+    let listener = bind(&socket).await?;
+    println!("bap daemon listening on {}", socket.display());
+
+    let (events, _) = broadcast::channel(256);
+    let daemon = Arc::new(Daemon {
+        agents: Mutex::new(BTreeMap::new()),
+        events,
+        next_id: AtomicU64::new(1),
+        shutting_down: Mutex::new(false),
+        agent_exited: Notify::new(),
+    });
+
+    tokio::spawn(track_status(daemon.clone(), daemon.events.subscribe()));
+    if let Some(addr) = metrics_addr {
+        metrics::start(addr, &daemon.events).await?;
+    }
+    notify::start(Config::load()?.notifications, &daemon.events)?;
+
+    let mut signals = ForwardedSignals::new()?;
+    loop {
+        tokio::select! {
+            connection = listener.accept() => {
+                let (stream, _) = connection.context("Failed to accept connection")?;
+                let daemon = daemon.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(daemon, stream).await {
+                        eprintln!("Control connection failed: {:#}", e);
+                    }
+                });
+            }
+            signal = signals.recv() => {
+                daemon.forward_signal(signal);
+                if is_stop_signal(signal) {
+                    println!("Received {}, stopping all agents", signal_name(signal));
+                    *daemon.shutting_down.lock().unwrap() = true;
+                }
+            }
+            _ = daemon.agent_exited.notified() => {}
+        }
+
+        if *daemon.shutting_down.lock().unwrap() && daemon.agents.lock().unwrap().is_empty() {
+            break;
+        }
+    }
+
+    let _ = fs::remove_file(&socket);
+    println!("bap daemon stopped");
+    Ok(())
+}
+
+async fn bind(socket: &Path) -> Result<UnixListener> {
+    if socket.exists() {
+        if UnixStream::connect(socket).await.is_ok() {
+            anyhow::bail!("A bap daemon is already listening on {}", socket.display());
+        }
+        // Left behind by a daemon that didn't shut down cleanly
+        fs::remove_file(socket)?;
+    }
+
+    if let Some(parent) = socket.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+impl Daemon {
+    fn handle(self: &Arc<Self>, request: Request) -> Result<Value> {
+        match request {
+            Request::List => {
+                let agents = self.agents.lock().unwrap();
+                let statuses: Vec<&AgentStatus> = agents.values().map(|a| &a.status).collect();
+                Ok(json!({ "ok": true, "agents": statuses }))
+            }
+            Request::Start { version, args } => {
+                let status = self.start_agent(&version, args)?;
+                Ok(json!({ "ok": true, "agent": status }))
+            }
+            Request::Stop { id, cancel } => {
+                let agents = self.agents.lock().unwrap();
+                let agent = agents
+                    .get(&id)
+                    .with_context(|| format!("No agent with id {}", id))?;
+                let _ = agent.signals.send(libc::SIGTERM);
+                if cancel {
+                    let _ = agent.signals.send(libc::SIGTERM);
+                }
+                Ok(json!({ "ok": true }))
+            }
+            Request::Restart { id } => {
+                let mut agents = self.agents.lock().unwrap();
+                let agent = agents
+                    .get_mut(&id)
+                    .with_context(|| format!("No agent with id {}", id))?;
+                agent.restart = true;
+                let _ = agent.signals.send(libc::SIGTERM);
+                Ok(json!({ "ok": true }))
+            }
+            Request::Logs { id, lines } => {
+                let agents = self.agents.lock().unwrap();
+                let agent = agents
+                    .get(&id)
+                    .with_context(|| format!("No agent with id {}", id))?;
+                Ok(json!({ "ok": true, "lines": agent.recent.last(lines) }))
+            }
+            Request::Subscribe => unreachable!("subscriptions are handled by the connection"),
+        }
+    }
+
+    fn start_agent(
+        self: &Arc<Self>,
+        version: &str,
+        extra_args: Vec<String>,
+    ) -> Result<AgentStatus> {
+        if *self.shutting_down.lock().unwrap() {
+            anyhow::bail!("The daemon is shutting down");
+        }
+
+        let version = version.trim_start_matches('v').to_string();
+        let argv = ["run".to_string(), version.clone()]
+            .into_iter()
+            .chain(extra_args.iter().cloned());
+        let args = RunCommand::try_parse_from(argv)
+            .map_err(|e| anyhow::anyhow!(e.to_string().trim().to_string()))?
+            .args;
+        if args.versions.len() > 1 || args.count > 1 {
+            anyhow::bail!("Each start request runs a single agent");
+        }
+        if args.events.is_some() || args.metrics_addr.is_some() {
+            anyhow::bail!(
+                "Start requests can't take --events or --metrics-addr, subscribe to the daemon's events or start it with --metrics-addr instead"
+            );
+        }
+
+        // There's no one to answer an install prompt here
+        let agent_path = bin_dir().join(&version).join("buildkite-agent");
+        if !agent_path.exists() {
+            anyhow::bail!("Buildkite agent version {} is not installed", version);
+        }
+
+        let layers = run::Layers::load(args.profile.as_deref(), args.token.as_deref())?;
+        let config = Config::load()?;
+        let settings = layers.settings(&config, &version);
+        layers.check_env(&settings)?;
+        // A missing token fails the request, there's no one to ask for one
+        run::preflight(&version, &settings, false)?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let recent = Arc::new(RecentLines::new(RECENT_LINES));
+        let (signals, signal_rx) = broadcast::channel(16);
+
+        let agent = Agent {
+            id: id.clone(),
+            version: version.clone(),
+            agent_path,
+            output: OutputMode::Discard,
+            prefix: Some(format!("[{}]", id)),
+            instance_args: Vec::new(),
+            log_path: args.log_file.as_ref().map(|path| {
+                path.clone()
+                    .unwrap_or_else(|| daemon_log_path(&version, &id))
+            }),
+            log_settings: config.logs,
+            observers: vec![recent.clone()],
+            events: Some(self.events.clone()),
+            settings,
+            profile: layers.profile,
+        };
+
+        let status = AgentStatus {
+            id: id.clone(),
+            version,
+            state: "starting".to_string(),
+            pid: None,
+            started_at: Utc::now().timestamp(),
+            restarts: 0,
+            last_exit_code: None,
+        };
+        self.agents.lock().unwrap().insert(
+            id.clone(),
+            ManagedAgent {
+                status: status.clone(),
+                args: extra_args,
+                restart: false,
+                signals,
+                recent,
+            },
+        );
+
+        let daemon = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run::supervise(&agent, &args, signal_rx).await {
+                eprintln!("[{}] Failed to run the agent: {:#}", agent.id, e);
+            }
+            let managed = daemon.agents.lock().unwrap().remove(&agent.id);
+            if let Some(managed) = managed.filter(|managed| managed.restart) {
+                match daemon.start_agent(&agent.version, managed.args) {
+                    Ok(status) => println!("[{}] Restarted as agent {}", agent.id, status.id),
+                    Err(e) => eprintln!("[{}] Failed to restart the agent: {:#}", agent.id, e),
+                }
+            }
+            daemon.agent_exited.notify_one();
+        });
+
+        Ok(status)
+    }
+
+    fn forward_signal(&self, signal: i32) {
+        for agent in self.agents.lock().unwrap().values() {
+            let _ = agent.signals.send(signal);
+        }
+    }
+}
+
+/// Keeps each agent's status up to date from the events its supervisor sends.
+async fn track_status(daemon: Arc<Daemon>, mut events: broadcast::Receiver<Event>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        let mut agents = daemon.agents.lock().unwrap();
+        let Some(agent) = event.agent.as_ref().and_then(|id| agents.get_mut(id)) else {
+            continue;
+        };
+        let status = &mut agent.status;
+
+        match event.kind {
+            EventKind::Spawned { pid } => {
+                status.state = "running".to_string();
+                status.pid = Some(pid);
+            }
+            EventKind::SignalReceived { ref signal }
+                if signal == "SIGINT" || signal == "SIGTERM" =>
+            {
+                status.state = "stopping".to_string();
+            }
+            EventKind::Exited { code, .. } => {
+                status.state = "exited".to_string();
+                status.pid = None;
+                status.last_exit_code = Some(code);
+            }
+            EventKind::Restarting { .. } => {
+                status.state = "restarting".to_string();
+                status.restarts += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn handle_connection(daemon: Arc<Daemon>, stream: UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let response = json!({ "ok": false, "error": format!("Invalid request: {}", e) });
+                write_message(&mut writer, &response).await?;
+                continue;
+            }
+        };
+
+        if let Request::Subscribe = request {
+            write_message(&mut writer, &json!({ "ok": true })).await?;
+            let mut events = daemon.events.subscribe();
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        // A write failing just means the subscriber went away
+                        if write_message(&mut writer, &json!({ "event": event }))
+                            .await
+                            .is_err()
+                        {
+                            return Ok(());
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+
+        let response = daemon
+            .handle(request)
+            .unwrap_or_else(|e| json!({ "ok": false, "error": format!("{:#}", e) }));
+        write_message(&mut writer, &response).await?;
+    }
+
+    Ok(())
+}
+
+async fn write_message(
+    writer: &mut tokio::net::unix::OwnedWriteHalf,
+    message: &Value,
+) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use crate::internal::history::{self, JobRecord};
+use crate::utils::{format_duration, parse_duration};
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+
+pub fn run(version: Option<&str>, since: Option<DateTime<Local>>, json: bool) -> Result<()> {
+    let jobs: Vec<JobRecord> = history::load()?
+        .into_iter()
+        .filter(|job| version.is_none_or(|version| job.version == version))
+        .filter(|job| match since {
+            Some(since) => job.finished_at().is_some_and(|finished| finished >= since),
+            None => true,
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&jobs)?);
+        return Ok(());
+    }
+
+    if jobs.is_empty() {
+        println!("No jobs found.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<19} {:<10} {:<30} {:<36} {:<8} STATUS",
+        "FINISHED", "VERSION", "PIPELINE", "JOB", "DURATION"
+    );
+    for job in jobs {
+        let finished = job
+            .finished_at()
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| job.finished_at.clone());
+        let duration = match (job.started_at(), job.finished_at()) {
+            (Some(started), Some(finished)) => (finished - started)
+                .to_std()
+                .map(format_duration)
+                .unwrap_or_default(),
+            _ => "-".to_string(),
+        };
+        let status = job
+            .exit_status
+            .map(|status| status.to_string())
+            .unwrap_or_else(|| "-".to_string());
+
+        println!(
+            "{:<19} {:<10} {:<30} {:<36} {:<8} {}",
+            finished,
+            job.version,
+            job.pipeline.as_deref().unwrap_or("-"),
+            job.job,
+            duration,
+            status
+        );
+    }
+
+    Ok(())
+}
+
+/// Parses `--since`, either how long ago (`30m`, `12h`, `7d`, `2w`) or a point
+/// in time (`2024-05-01` or an RFC 3339 timestamp).
+pub fn parse_since(value: &str) -> Result<DateTime<Local>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Local));
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).expect("midnight is a valid time");
+        return Local
+            .from_local_datetime(&midnight)
+            .earliest()
+            .ok_or_else(|| format!("{} doesn't exist in the local time zone", value));
+    }
+
+    let ago = parse_duration(value).map_err(|_| {
+        format!(
+            "expected a duration such as 12h or 7d, or a date such as 2024-05-01, got {}",
+            value
+        )
+    })?;
+    chrono::Duration::from_std(ago)
+        .ok()
+        .and_then(|ago| Local::now().checked_sub_signed(ago))
+        .ok_or_else(|| format!("{} is too long ago", value))
+}
@@ -1,3 +1,5 @@
+use crate::internal::agent_config::{config_path, AgentConfig};
+use crate::internal::token;
 use crate::utils::{bin_dir, versions_dir};
 use anyhow::{bail, Result};
 use flate2::read::GzDecoder;
@@ -49,6 +51,27 @@ pub async fn run(version: &str) -> Result<()> {
     update_versions_list(version_without_v)?;
 
     println!("🚀 {} installed... ", version_without_v,);
+
+    apply_global_token(version_without_v)?;
+    Ok(())
+}
+
+/// Writes the token saved with `bap auth --global` into a freshly installed
+/// version's config file, so it's ready to run.
+fn apply_global_token(version: &str) -> Result<()> {
+    let Some(token) = token::load_global()? else {
+        return Ok(());
+    };
+    let Some(mut config) = AgentConfig::load_if_exists(&config_path(version))? else {
+        return Ok(());
+    };
+    if !token::needs_token(Some(&config)) {
+        return Ok(());
+    }
+
+    config.set("token", &token);
+    config.save()?;
+    println!("🔑 Applied the global agent token to {}", version);
     Ok(())
 }
 
@@ -76,6 +99,7 @@ fn update_versions_list(version: &str) -> Result<()> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(versions_file)?;
 
     let mut content = String::new();
@@ -6,7 +6,8 @@ use std::io::{BufRead, BufReader};
 
 pub fn run() -> Result<()> {
     let versions = list_installed_versions()?;
-    let current_version = get_version()?;
+    // A range nothing installed matches has no current version to mark
+    let current_version = get_version().ok().flatten();
     let config = Config::load()?;
 
     if versions.is_empty() {
@@ -32,6 +33,8 @@ pub fn run() -> Result<()> {
     Ok(())
 }
 
+// Lines that can't be read are skipped rather than ending the list early.
+#[allow(clippy::lines_filter_map_ok)]
 pub fn list_installed_versions() -> Result<Vec<String>> {
     let versions_file_path = versions_file();
     let file = File::open(versions_file_path)?;
@@ -27,7 +27,7 @@ pub async fn select_version() -> Result<String> {
     let installed_versions = get_installed_versions()?;
 
     let mut page = 0;
-    let total_pages = (versions.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+    let total_pages = versions.len().div_ceil(PAGE_SIZE);
 
     println!("Select a remote Buildkite agent version");
 
@@ -117,7 +117,7 @@ fn handle_installed_version(version: &str) -> Result<()> {
         .interact()?;
 
     match selection {
-        0 => use_version::run(version)?,
+        0 => use_version::run(Some(version), None)?,
         1 => default::run(version)?,
         2 => println!("No action taken."),
         _ => unreachable!(),
@@ -143,7 +143,7 @@ async fn handle_not_installed_version(version: &str) -> Result<()> {
             .default(true)
             .interact()?
         {
-            use_version::run(version)?;
+            use_version::run(Some(version), None)?;
         }
     } else {
         println!("No action taken.");
@@ -0,0 +1,118 @@
+use crate::config;
+use crate::internal::log_file::instance_log_path;
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Which log file to read: a path given outright, or one `bap run --log-file`
+/// wrote for a version and, with `--count`, one of its agents.
+pub enum Source<'a> {
+    File(PathBuf),
+    Version {
+        version: Option<&'a str>,
+        instance: u32,
+    },
+}
+
+pub async fn run(source: Source<'_>, follow: bool, lines: Option<usize>) -> Result<()> {
+    let path = match source {
+        Source::File(path) => {
+            if !path.exists() {
+                anyhow::bail!("No log file at {}.", path.display());
+            }
+            path
+        }
+        Source::Version { version, instance } => {
+            let version = match version {
+                Some(v) => v.to_string(),
+                None => config::get_version()?.context(
+                    "No version specified and no local or default version set. Use `bap logs <version>`.",
+                )?,
+            };
+            let path = instance_log_path(&version, instance);
+            if !path.exists() {
+                match instance {
+                    1 => anyhow::bail!(
+                        "No logs found for version {}. Capture them with `bap run {} --log-file`.",
+                        version,
+                        version
+                    ),
+                    n => anyhow::bail!(
+                        "No logs found for agent {} of version {}. Capture them with `bap run {} --count {} --log-file`.",
+                        n,
+                        version,
+                        version,
+                        n
+                    ),
+                }
+            }
+            path
+        }
+    };
+
+    let content =
+        fs::read(&path).with_context(|| format!("Failed to read log file: {}", path.display()))?;
+    io::stdout().write_all(tail(&content, lines))?;
+
+    if follow {
+        follow_file(&path, content.len() as u64).await?;
+    }
+
+    Ok(())
+}
+
+fn tail(content: &[u8], lines: Option<usize>) -> &[u8] {
+    match lines {
+        None => content,
+        Some(0) => &[],
+        Some(lines) => {
+            let trimmed = content.strip_suffix(b"\n").unwrap_or(content);
+            let start = trimmed
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, &b)| b == b'\n')
+                .nth(lines - 1)
+                .map_or(0, |(i, _)| i + 1);
+            &content[start..]
+        }
+    }
+}
+
+/// Prints whatever gets appended to the log from `offset` onwards, picking up
+/// the new file when `bap run` rotates the current one away.
+async fn follow_file(path: &Path, mut offset: u64) -> Result<()> {
+    let mut inode = fs::metadata(path)?.ino();
+    let mut stdout = io::stdout();
+
+    loop {
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+
+        let Ok(metadata) = fs::metadata(path) else {
+            continue;
+        };
+
+        if metadata.ino() != inode || metadata.len() < offset {
+            inode = metadata.ino();
+            offset = 0;
+        }
+
+        if metadata.len() == offset {
+            continue;
+        }
+
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut appended = Vec::new();
+        file.read_to_end(&mut appended)?;
+        offset += appended.len() as u64;
+
+        stdout.write_all(&appended)?;
+        stdout.flush()?;
+    }
+}
@@ -1,9 +1,19 @@
 pub mod auth;
+pub mod config;
+pub mod daemon;
 pub mod default;
+pub mod history;
 pub mod install;
 pub mod list;
 pub mod list_remote;
+pub mod logs;
+pub mod profile;
+pub mod ps;
 pub mod run;
+pub mod service;
+pub mod start;
+pub mod stop;
+pub mod top;
 pub mod uninstall;
 pub mod use_version;
 pub mod version;
@@ -0,0 +1,100 @@
+use crate::commands::run::parse_tag;
+use crate::config::TokenProvider;
+use crate::internal::profile::{self, profile_path, Profile};
+use crate::internal::token;
+use anyhow::Result;
+use clap::Args;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub struct CreateArgs {
+    /// The name to save the profile as, e.g. linux-docker
+    pub name: String,
+
+    /// Add a tag to the agent, merged with the tags in its config (repeatable)
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tags: Vec<String>,
+
+    /// The queue the agent should pull jobs from
+    #[arg(long)]
+    pub queue: Option<String>,
+
+    /// Where the agent checks out and runs builds
+    #[arg(long, value_name = "PATH")]
+    pub build_path: Option<PathBuf>,
+
+    /// Where the agent looks for hooks
+    #[arg(long, value_name = "PATH")]
+    pub hooks_path: Option<PathBuf>,
+
+    /// Where the agent keeps plugins
+    #[arg(long, value_name = "PATH")]
+    pub plugins_path: Option<PathBuf>,
+
+    /// Connect with the token saved under this name by `bap auth --name`
+    #[arg(long, value_name = "NAME")]
+    pub token: Option<String>,
+
+    /// Replace a profile that already exists
+    #[arg(long)]
+    pub force: bool,
+}
+
+pub fn create(args: &CreateArgs) -> Result<()> {
+    if profile_path(&args.name)?.exists() && !args.force {
+        anyhow::bail!(
+            "A profile named {} already exists, pass --force to replace it",
+            args.name
+        );
+    }
+    if let Some(name) = &args.token {
+        token::existing_named_token_path(name)?;
+    }
+
+    let mut profile = Profile {
+        tags: args.tags.clone(),
+        queue: args.queue.clone(),
+        build_path: args.build_path.clone(),
+        hooks_path: args.hooks_path.clone(),
+        plugins_path: args.plugins_path.clone(),
+        ..Profile::default()
+    };
+    profile.agent.token = args.token.clone().map(TokenProvider::Named);
+    profile.save(&args.name)?;
+
+    println!(
+        "Created profile {}, run it with `bap run --profile {}`",
+        args.name, args.name
+    );
+    Ok(())
+}
+
+pub fn list() -> Result<()> {
+    let names = profile::list()?;
+    if names.is_empty() {
+        println!("No profiles found. Create one with `bap profile create <name>`.");
+        return Ok(());
+    }
+
+    for name in names {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+pub fn show(name: &str) -> Result<()> {
+    let profile = Profile::load(name)?;
+    println!("{}", serde_json::to_string_pretty(&profile)?);
+    Ok(())
+}
+
+pub fn delete(name: &str) -> Result<()> {
+    let path = profile_path(name)?;
+    if !path.exists() {
+        anyhow::bail!("There's no profile named {}", name);
+    }
+    fs::remove_file(&path)?;
+    println!("Deleted profile {}", name);
+    Ok(())
+}
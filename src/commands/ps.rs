@@ -0,0 +1,30 @@
+use crate::internal::instances;
+use crate::utils::format_duration;
+use anyhow::Result;
+
+pub fn run() -> Result<()> {
+    let instances = instances::list()?;
+
+    if instances.is_empty() {
+        println!("No background Buildkite agents running.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<4} {:<10} {:<8} {:<8} {:<16} CWD",
+        "ID", "VERSION", "PID", "UPTIME", "PROFILE"
+    );
+    for instance in instances {
+        println!(
+            "{:<4} {:<10} {:<8} {:<8} {:<16} {}",
+            instance.id,
+            instance.version,
+            instance.pid,
+            format_duration(instance.uptime()),
+            instance.profile.as_deref().unwrap_or("-"),
+            instance.cwd.display()
+        );
+    }
+
+    Ok(())
+}
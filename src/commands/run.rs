@@ -1,78 +1,852 @@
 use crate::commands::install;
-use crate::config;
-use crate::utils::bin_dir;
+use crate::config::{self, AgentSettings, LogSettings, TokenProvider};
+use crate::internal::agent_config::AgentConfig;
+use crate::internal::api::GitHubAPI;
+use crate::internal::events::{
+    open_sink, write_json_lines, Event, EventFormat, EventKind, LogWatcher,
+};
+use crate::internal::history::HistoryRecorder;
+use crate::internal::log_file::{instance_log_path, LogRecorder, RotatingLog};
+use crate::internal::metrics;
+use crate::internal::notify;
+use crate::internal::output::{Observers, OutputForwarder, OutputMode};
+use crate::internal::process;
+use crate::internal::profile::Profile;
+use crate::internal::project::{ProjectConfig, VersionSpec, PROJECT_FILE};
+use crate::internal::restart::{RestartDecision, RestartPolicy, RestartTracker};
+use crate::internal::schedule::{parse_window, Schedule, Window};
+use crate::internal::signals::{is_stop_signal, send_signal, signal_name, ForwardedSignals};
+use crate::internal::token;
+use crate::utils::{bin_dir, builds_dir, format_duration, parse_duration};
 use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::Args;
+use console::Style;
 use dialoguer::Confirm;
-use std::path::PathBuf;
-use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command as AsyncCommand;
-use tokio::signal::ctrl_c;
+use futures_util::future::join_all;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::pending;
+use std::net::SocketAddr;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use tokio::process::{Child, Command as AsyncCommand};
+use tokio::sync::broadcast;
+use tokio::time::{sleep_until, Duration, Instant};
 
-pub async fn run(specified_version: Option<&str>) -> Result<()> {
-    let version = match specified_version {
-        Some(v) => v.to_string(),
-        None => get_version().await?,
+#[derive(Args)]
+pub struct RunArgs {
+    /// The versions of the Buildkite agent to run, side by side if more than one (optional)
+    pub versions: Vec<String>,
+
+    /// How many agents to run of each version
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub count: u32,
+
+    /// Add a tag to the agent, merged with the tags in its config (repeatable)
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tags: Vec<String>,
+
+    /// The queue the agent should pull jobs from
+    #[arg(long)]
+    pub queue: Option<String>,
+
+    /// Layer a profile saved with `bap profile create` over the version's config
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Connect with the token saved under this name with `bap auth --name`
+    #[arg(long, value_name = "NAME")]
+    pub token: Option<String>,
+
+    /// Seconds to wait for the agent to stop after SIGINT/SIGTERM before killing it
+    #[arg(long, value_name = "SECONDS")]
+    pub grace_timeout: Option<u64>,
+
+    /// Also write the agent's output to a rotating log file, by default
+    /// ~/.bap/logs/<version>/agent.log
+    #[arg(long, value_name = "PATH", num_args = 0..=1)]
+    pub log_file: Option<Option<PathBuf>>,
+
+    /// Restart the agent when it exits without being asked to
+    #[arg(long, value_enum, default_value_t = RestartPolicy::No)]
+    pub restart: RestartPolicy,
+
+    /// Give up restarting after this many restarts within the restart window
+    #[arg(long, default_value_t = 5)]
+    pub max_restarts: usize,
+
+    /// The window, in seconds, that --max-restarts applies to
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    pub restart_window: u64,
+
+    /// Only run the agent within this window, e.g. "weekdays 18:00-08:00"
+    /// (repeatable). Outside it the agent finishes its current job and stops
+    /// until the window opens again
+    #[arg(long, value_name = "WINDOW", value_parser = parse_window)]
+    pub schedule: Vec<Window>,
+
+    /// Stop the agent once its current job finishes after it has run this
+    /// long, e.g. 8h. With --schedule it starts again when the next window opens
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub max_duration: Option<Duration>,
+
+    /// Serve Prometheus metrics about the agents on this address, e.g. 127.0.0.1:9090
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Write lifecycle events in this format, to --events-file or --events-fd
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub events: Option<EventFormat>,
+
+    /// Append events to this file
+    #[arg(long, value_name = "PATH", requires = "events")]
+    pub events_file: Option<PathBuf>,
+
+    /// Write events to this already open file descriptor (default 3)
+    #[arg(
+        long,
+        value_name = "FD",
+        requires = "events",
+        conflicts_with = "events_file"
+    )]
+    pub events_fd: Option<i32>,
+
+    /// Extra arguments passed verbatim to `buildkite-agent start`
+    #[arg(last = true, value_name = "AGENT_ARGS")]
+    pub agent_args: Vec<String>,
+}
+
+/// One agent process that bap looks after.
+pub struct Agent {
+    /// Identifies the agent in lifecycle events.
+    pub id: String,
+    pub version: String,
+    pub agent_path: PathBuf,
+    pub output: OutputMode,
+    /// Put in front of bap's own messages about the agent.
+    pub prefix: Option<String>,
+    /// Arguments that keep this agent apart from the others running alongside it.
+    pub instance_args: Vec<String>,
+    pub log_path: Option<PathBuf>,
+    /// When to rotate the log file.
+    pub log_settings: LogSettings,
+    /// Observers to hand the agent's output to, on top of its log file.
+    pub observers: Observers,
+    pub events: Option<broadcast::Sender<Event>>,
+    /// The environment and limits to start the agent process with.
+    pub settings: AgentSettings,
+    /// The profile given with `--profile`, with the project's settings on top,
+    /// whose tags, queue and paths are added when the agent starts.
+    pub profile: Option<Profile>,
+}
+
+impl Agent {
+    fn say(&self, message: impl Display) {
+        match &self.prefix {
+            Some(prefix) => println!("{} {}", prefix, message),
+            None => println!("{}", message),
+        }
+    }
+
+    fn emit(&self, kind: EventKind) {
+        if let Some(events) = &self.events {
+            // Nobody listening is fine, events are only ever informational
+            let _ = events.send(Event::new(&self.id, &self.version, kind));
+        }
+    }
+}
+
+pub async fn run(args: &RunArgs) -> Result<i32> {
+    let config = config::Config::load()?;
+    // Lifecycle events are only collected when something wants them
+    let events =
+        (args.events.is_some() || args.metrics_addr.is_some() || !config.notifications.is_empty())
+            .then(|| broadcast::channel::<Event>(256).0);
+
+    let event_writer = match (&events, args.events) {
+        (Some(events), Some(EventFormat::Json)) => {
+            let sink = open_sink(args.events_file.as_deref(), args.events_fd)?;
+            Some(tokio::spawn(write_json_lines(sink, events.subscribe())))
+        }
+        _ => None,
     };
 
-    let agent_path = ensure_version_installed(&version).await?;
+    if let (Some(events), Some(addr)) = (&events, args.metrics_addr) {
+        metrics::start(addr, events).await?;
+    }
 
-    println!("Running Buildkite agent version {}...", version);
+    let notifier = match &events {
+        Some(events) => notify::start(config.notifications.clone(), events)?,
+        None => None,
+    };
 
-    let mut child = AsyncCommand::new(agent_path)
-        .arg("start")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("Failed to start buildkite-agent")?;
-
-    let stdout = child.stdout.take().expect("Failed to capture stdout");
-    let stderr = child.stderr.take().expect("Failed to capture stderr");
-
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
-
-    let output_handler = tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                result = stdout_reader.next_line() => {
-                    match result {
-                        Ok(Some(line)) => println!("{}", line),
-                        Ok(None) => break,
-                        Err(e) => eprintln!("Error reading stdout: {}", e),
-                    }
+    let agents = plan_agents(args, &config, events.as_ref()).await?;
+
+    let results = supervise_all(&agents, args).await?;
+
+    // Everything holding a sender has to go before the writer and notifier
+    // see the end
+    drop(agents);
+    drop(events);
+    if let Some(event_writer) = event_writer {
+        let _ = event_writer.await;
+    }
+    if let Some(notifier) = notifier {
+        let _ = notifier.await;
+    }
+
+    let codes = results.into_iter().collect::<Result<Vec<_>>>()?;
+    Ok(codes.into_iter().find(|&code| code != 0).unwrap_or(0))
+}
+
+/// Runs every agent at once. Signals are caught once here and handed to each
+/// of them, so a Ctrl-C stops them all together.
+async fn supervise_all(agents: &[Agent], args: &RunArgs) -> Result<Vec<Result<i32>>> {
+    let mut signals = ForwardedSignals::new()?;
+    let (signal_tx, _) = broadcast::channel(16);
+    let supervisors = join_all(
+        agents
+            .iter()
+            .map(|agent| supervise(agent, args, signal_tx.subscribe())),
+    );
+    tokio::pin!(supervisors);
+
+    loop {
+        tokio::select! {
+            results = &mut supervisors => return Ok(results),
+            signal = signals.recv() => {
+                if signal == libc::SIGINT {
+                    println!();
                 }
-                result = stderr_reader.next_line() => {
-                    match result {
-                        Ok(Some(line)) => eprintln!("{}", line),
-                        Ok(None) => break,
-                        Err(e) => eprintln!("Error reading stderr: {}", e),
-                    }
+                let _ = signal_tx.send(signal);
+            }
+        }
+    }
+}
+
+async fn plan_agents(
+    args: &RunArgs,
+    config: &config::Config,
+    events: Option<&broadcast::Sender<Event>>,
+) -> Result<Vec<Agent>> {
+    let mut resolved = Vec::new();
+    if args.versions.is_empty() {
+        resolved.push(resolve_agent_with_events(None, events).await?);
+    }
+    for version in &args.versions {
+        resolved.push(resolve_agent_with_events(Some(version), events).await?);
+    }
+
+    let total = resolved.len() * args.count as usize;
+    if total > 1 && matches!(args.log_file, Some(Some(_))) {
+        anyhow::bail!(
+            "--log-file <PATH> can only be used with a single agent, leave out the path to log each agent to its own file"
+        );
+    }
+
+    let layers = Layers::load(args.profile.as_deref(), args.token.as_deref())?;
+    let profile = &layers.profile;
+    let build_root = profile
+        .as_ref()
+        .and_then(|profile| profile.build_path.clone())
+        .unwrap_or_else(builds_dir);
+    let mut agents = Vec::new();
+    let mut per_version: HashMap<String, u32> = HashMap::new();
+    for (version, agent_path) in resolved {
+        for _ in 0..args.count {
+            let number = per_version.entry(version.clone()).or_default();
+            *number += 1;
+            let number = *number;
+
+            let id = format!("{}#{}", version, number);
+            let (prefix, instance_args) = if total > 1 {
+                let label = format!("[{}]", id);
+                let instance = format!("{}-{}", version, number);
+                (
+                    Some(prefix_style(agents.len()).apply_to(label).to_string()),
+                    vec![
+                        "--name".to_string(),
+                        format!("%hostname-%spawn-{}", instance),
+                        "--build-path".to_string(),
+                        build_root.join(instance).display().to_string(),
+                    ],
+                )
+            } else {
+                (None, Vec::new())
+            };
+
+            let settings = layers.settings(config, &version);
+            layers.check_env(&settings)?;
+            // Once per version is enough, they all share its config file
+            if number == 1 {
+                preflight(&version, &settings, true)?;
+            }
+            let log_path = args.log_file.as_ref().map(|path| {
+                path.clone()
+                    .unwrap_or_else(|| instance_log_path(&version, number))
+            });
+
+            agents.push(Agent {
+                id,
+                version: version.clone(),
+                agent_path: agent_path.clone(),
+                output: prefix.clone().map_or(OutputMode::Raw, OutputMode::Prefixed),
+                prefix,
+                instance_args,
+                log_path,
+                log_settings: config.logs.clone(),
+                observers: Vec::new(),
+                events: events.cloned(),
+                settings,
+                profile: profile.clone(),
+            });
+        }
+    }
+
+    Ok(agents)
+}
+
+/// What the command line and the current directory layer over the config's
+/// settings for each version: a profile, `.bap.toml` and a named token.
+pub struct Layers {
+    project: Option<ProjectConfig>,
+    /// The profile asked for, with `.bap.toml` layered over it.
+    pub profile: Option<Profile>,
+    token: Option<TokenProvider>,
+}
+
+impl Layers {
+    /// `profile` and `token` are the ones named on the command line, which win
+    /// over any the directory picks.
+    pub fn load(profile: Option<&str>, token: Option<&str>) -> Result<Layers> {
+        let project = ProjectConfig::load()?;
+        let profile_name = profile.or(project
+            .as_ref()
+            .and_then(|project| project.profile.as_deref()));
+        let mut profile = profile_name.map(Profile::load).transpose()?;
+        if let Some(project) = &project {
+            profile = Some(project.layer_over(profile.unwrap_or_default())?);
+        }
+        let token = token
+            .map(str::to_string)
+            .or(config::LocalConfig::load()?.token)
+            .map(TokenProvider::Named);
+
+        Ok(Layers {
+            project,
+            profile,
+            token,
+        })
+    }
+
+    /// The settings an agent of `version` runs with. A token named on the
+    /// command line wins over the one the directory picked, and both win over
+    /// a profile's or the config's.
+    pub fn settings(&self, config: &config::Config, version: &str) -> AgentSettings {
+        let mut settings = config.agent_settings(version);
+        if let Some(profile) = &self.profile {
+            settings = settings.overlay(&profile.agent);
+        }
+        settings.token = self.token.clone().or(settings.token);
+        settings
+    }
+
+    /// Fails if `.bap.toml` requires variables that neither bap's environment
+    /// nor `settings` set.
+    pub fn check_env(&self, settings: &AgentSettings) -> Result<()> {
+        let Some(project) = &self.project else {
+            return Ok(());
+        };
+        let missing = project.missing_env(&settings.env);
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "{} needs these environment variables set to run the agent: {}",
+                PROJECT_FILE,
+                missing.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Checks the config of `version` before an agent runs with `settings`,
+/// returning whether it was all fine. `may_prompt` says whether there's
+/// someone to ask for a missing token.
+pub fn preflight(version: &str, settings: &AgentSettings, may_prompt: bool) -> Result<bool> {
+    let token_elsewhere = token::supplied_elsewhere(settings.token.as_ref())?;
+    crate::commands::config::preflight(version, token_elsewhere, may_prompt)
+}
+
+fn prefix_style(index: usize) -> Style {
+    let style = Style::new().bold();
+    match index % 6 {
+        0 => style.cyan(),
+        1 => style.magenta(),
+        2 => style.yellow(),
+        3 => style.green(),
+        4 => style.blue(),
+        _ => style.red(),
+    }
+}
+
+/// Runs a single agent until it stops for good, restarting it as the restart
+/// policy allows. Signals sent down `signals` are handled as if `bap run` had
+/// received them itself.
+pub async fn supervise(
+    agent: &Agent,
+    args: &RunArgs,
+    mut signals: broadcast::Receiver<i32>,
+) -> Result<i32> {
+    let code = supervise_until_stopped(agent, args, &mut signals).await?;
+    agent.emit(EventKind::Stopped { code });
+    Ok(code)
+}
+
+async fn supervise_until_stopped(
+    agent: &Agent,
+    args: &RunArgs,
+    signals: &mut broadcast::Receiver<i32>,
+) -> Result<i32> {
+    agent.say(format!(
+        "Running Buildkite agent version {}...",
+        agent.version
+    ));
+
+    let mut observers = agent.observers.clone();
+    observers.push(Arc::new(HistoryRecorder::new(&agent.version)));
+    if let Some(events) = &agent.events {
+        observers.push(Arc::new(LogWatcher::new(
+            &agent.id,
+            &agent.version,
+            events.clone(),
+        )));
+    }
+    if let Some(path) = &agent.log_path {
+        let log = RotatingLog::open(path, agent.log_settings.clone())?;
+        agent.say(format!("Logging agent output to {}", path.display()));
+        observers.push(Arc::new(LogRecorder::new(log)));
+    }
+
+    let mut stop = StopState::default();
+    let mut restarts = RestartTracker::new(
+        args.restart,
+        args.max_restarts,
+        Duration::from_secs(args.restart_window),
+    );
+    let schedule = Schedule::new(&args.schedule);
+    // After running for --max-duration the agent sits out the rest of its window
+    let mut resume_after: Option<DateTime<Local>> = None;
+    let mut last_code = 0;
+
+    loop {
+        if let Some(schedule) = &schedule {
+            let opens = schedule.next_open(resume_after.take().unwrap_or_else(Local::now));
+            if let Ok(wait) = (opens - Local::now()).to_std() {
+                agent.emit(EventKind::Waiting {
+                    until: opens.to_rfc3339(),
+                });
+                agent.say(format!(
+                    "Outside the run window, starting the agent at {}",
+                    opens.format("%a %H:%M")
+                ));
+                if let Some(signal) = wait_unless_stopped(wait, signals).await {
+                    agent.say(format!(
+                        "Received {} while waiting for the run window, not starting the agent",
+                        signal_name(signal)
+                    ));
+                    return Ok(last_code);
                 }
             }
         }
+
+        let mut child = build_command(agent, args)
+            .await?
+            .spawn()
+            .context("Failed to start buildkite-agent")?;
+        let started = Instant::now();
+        if let Some(pid) = child.id() {
+            agent.emit(EventKind::Spawned { pid });
+        }
+
+        let stdout = child.stdout.take().expect("Failed to capture stdout");
+        let stderr = child.stderr.take().expect("Failed to capture stderr");
+        let output =
+            OutputForwarder::spawn(stdout, stderr, observers.clone(), agent.output.clone());
+
+        let pause = next_pause(schedule.as_ref(), args.max_duration);
+        let status = wait_for_exit(
+            agent,
+            &mut child,
+            signals,
+            &mut stop,
+            args.grace_timeout,
+            pause,
+        )
+        .await;
+        output.finish().await?;
+
+        let status = status.context("Failed to wait for buildkite-agent process")?;
+        let (code, description) = describe_exit(status);
+        agent.emit(EventKind::Exited {
+            code,
+            description: description.clone(),
+            requested: stop.reason.is_some(),
+        });
+
+        if let Some(reason) = &stop.reason {
+            agent.say(format!("Buildkite agent {} {}", description, reason));
+            return Ok(code);
+        }
+
+        if let Some(pause) = stop.paused.take() {
+            agent.say(format!(
+                "Buildkite agent {} {}",
+                description,
+                pause.describe()
+            ));
+            let Some(schedule) = &schedule else {
+                return Ok(code);
+            };
+            if let Pause::MaxDuration(_) = pause {
+                resume_after = Some(schedule.next_close(Local::now()));
+            }
+            stop = StopState::default();
+            last_code = code;
+            continue;
+        }
+
+        let delay = match restarts.decide(code, started.elapsed()) {
+            RestartDecision::Stop => {
+                agent.say(format!("Buildkite agent {} on its own", description));
+                return Ok(code);
+            }
+            RestartDecision::GiveUp => {
+                agent.emit(EventKind::GaveUp {
+                    restarts: restarts.recent_restarts(),
+                });
+                agent.say(format!(
+                    "Buildkite agent {} on its own, giving up after {} restarts within {}s",
+                    description,
+                    restarts.recent_restarts(),
+                    restarts.window().as_secs()
+                ));
+                return Ok(code);
+            }
+            RestartDecision::After(delay) => delay,
+        };
+        agent.emit(EventKind::Restarting {
+            delay_secs: delay.as_secs(),
+            restarts: restarts.recent_restarts(),
+        });
+
+        agent.say(format!(
+            "Buildkite agent {} on its own, restarting in {}s (restart {} of {} within {}s)",
+            description,
+            delay.as_secs(),
+            restarts.recent_restarts(),
+            restarts.max_restarts(),
+            restarts.window().as_secs()
+        ));
+
+        if let Some(signal) = wait_unless_stopped(delay, signals).await {
+            agent.say(format!(
+                "Received {} while waiting to restart, not restarting the agent",
+                signal_name(signal)
+            ));
+            return Ok(code);
+        }
+    }
+}
+
+async fn build_command(agent: &Agent, args: &RunArgs) -> Result<AsyncCommand> {
+    let mut command = AsyncCommand::new(&agent.agent_path);
+    command.arg("start");
+
+    let profile = agent.profile.as_ref();
+    if let Some(profile) = profile {
+        command.args(profile.path_args(agent.instance_args.is_empty()));
+    }
+    command.args(&agent.instance_args);
+
+    let config_path = agent.agent_path.with_file_name("buildkite-agent.cfg");
+    let tags = match profile {
+        Some(profile) => [profile.tags.clone(), args.tags.clone()].concat(),
+        None => args.tags.clone(),
+    };
+    let queue = args
+        .queue
+        .as_deref()
+        .or(profile.and_then(|profile| profile.queue.as_deref()));
+    if !tags.is_empty() || queue.is_some() {
+        let tags = merge_tags(&config_path, &tags, queue)?;
+        command.arg("--tags").arg(tags.join(","));
+    }
+
+    process::apply_settings(&mut command, &agent.settings)?;
+
+    // Fetched on every start so an agent that restarts picks up a rotated token
+    let config = AgentConfig::load_if_exists(&config_path)?;
+    if let Some(token) =
+        token::token_for_agent(agent.settings.token.as_ref(), config.as_ref()).await?
+    {
+        command.env(token::TOKEN_ENV_VAR, token);
+    }
+
+    // The agent gets its own process group so a Ctrl-C in the terminal reaches
+    // bap alone, which then decides what the agent should be sent.
+    command
+        .args(&args.agent_args)
+        .process_group(0)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    Ok(command)
+}
+
+/// Tracks the stop signals bap has received over the whole run, so a Ctrl-C
+/// always moves shutdown on a stage, however many times the agent restarted.
+#[derive(Default)]
+struct StopState {
+    requests: u32,
+    grace_deadline: Option<Instant>,
+    reason: Option<String>,
+    /// Set when bap stopped the agent for its schedule rather than because it
+    /// was asked to.
+    paused: Option<Pause>,
+}
+
+/// Why bap is stopping the agent for a while.
+#[derive(Clone, Copy)]
+enum Pause {
+    WindowClosed,
+    MaxDuration(Duration),
+}
+
+impl Pause {
+    fn describe(&self) -> String {
+        match self {
+            Pause::WindowClosed => "at the end of its run window".to_string(),
+            Pause::MaxDuration(limit) => format!(
+                "after reaching its maximum run duration of {}",
+                format_duration(*limit)
+            ),
+        }
+    }
+}
+
+/// When the agent that's about to start should be stopped again, and why:
+/// whichever comes first of its run window closing and its maximum duration.
+fn next_pause(
+    schedule: Option<&Schedule>,
+    max_duration: Option<Duration>,
+) -> Option<(Instant, Pause)> {
+    let window_closes = schedule.map(|schedule| {
+        let wait = (schedule.next_close(Local::now()) - Local::now())
+            .to_std()
+            .unwrap_or_default();
+        (Instant::now() + wait, Pause::WindowClosed)
     });
+    let time_up = max_duration.map(|limit| (Instant::now() + limit, Pause::MaxDuration(limit)));
+
+    match (window_closes, time_up) {
+        (Some(closes), Some(up)) => Some(if up.0 < closes.0 { up } else { closes }),
+        (closes, up) => closes.or(up),
+    }
+}
+
+/// Waits for the agent to exit, forwarding signals to it along the way.
+async fn wait_for_exit(
+    agent: &Agent,
+    child: &mut Child,
+    signals: &mut broadcast::Receiver<i32>,
+    stop: &mut StopState,
+    grace_timeout: Option<u64>,
+    mut pause: Option<(Instant, Pause)>,
+) -> Result<ExitStatus> {
+    let pid = child
+        .id()
+        .context("Failed to get buildkite-agent process id")?;
+
+    loop {
+        let grace_timeout_elapsed = async {
+            match stop.grace_deadline {
+                Some(deadline) => sleep_until(deadline).await,
+                None => pending().await,
+            }
+        };
+        let pause_due = async {
+            match pause {
+                Some((at, _)) => sleep_until(at).await,
+                None => pending().await,
+            }
+        };
+
+        tokio::select! {
+            status = child.wait() => return Ok(status?),
+            signal = next_signal(signals) => {
+                let name = signal_name(signal);
+                agent.emit(EventKind::SignalReceived { signal: name.to_string() });
 
-    tokio::select! {
-        _ = ctrl_c() => {
-            println!("");
-            child.kill().await.context("Failed to kill buildkite-agent process")?;
+                if !is_stop_signal(signal) {
+                    send_signal(pid, signal)?;
+                    agent.say(format!("Forwarded {} to the Buildkite agent", name));
+                    continue;
+                }
+
+                stop.requests += 1;
+                stop.reason = Some(format!("after {}", name));
+                match stop.requests {
+                    1 => {
+                        send_signal(pid, signal)?;
+                        agent.say(format!(
+                            "Received {}, waiting for the agent to finish its current job (send again to cancel it)",
+                            name
+                        ));
+                        stop.grace_deadline =
+                            grace_timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+                    }
+                    2 => {
+                        send_signal(pid, signal)?;
+                        agent.say(format!(
+                            "Received {} again, asking the agent to cancel its current job (send again to kill it)",
+                            name
+                        ));
+                    }
+                    _ => {
+                        agent.say(format!("Received {} again, killing the agent", name));
+                        child.start_kill().context("Failed to kill buildkite-agent process")?;
+                    }
+                }
+            }
+            _ = pause_due => {
+                let Some((_, reason)) = pause.take() else {
+                    continue;
+                };
+                agent.emit(EventKind::Pausing { reason: reason.describe() });
+                // The agent treats this like a first Ctrl-C, so count it as one
+                send_signal(pid, libc::SIGTERM)?;
+                stop.requests += 1;
+                stop.paused = Some(reason);
+                let why = match reason {
+                    Pause::WindowClosed => "The run window has closed".to_string(),
+                    Pause::MaxDuration(limit) => format!(
+                        "The agent has run for {}",
+                        format_duration(limit)
+                    ),
+                };
+                agent.say(format!(
+                    "{}, stopping the agent once its current job finishes",
+                    why
+                ));
+            }
+            _ = grace_timeout_elapsed => {
+                agent.say("Agent still running after the grace timeout, killing it");
+                child.start_kill().context("Failed to kill buildkite-agent process")?;
+                stop.grace_deadline = None;
+                stop.reason = Some("after the grace timeout".to_string());
+            }
         }
-        status = child.wait() => {
-            if let Err(e) = status {
-                eprintln!("Buildkite agent process error: {}", e);
+    }
+}
+
+/// Sleeps while there's no agent running, such as during the restart backoff.
+/// Returns the stop signal that cut it short, if any; other signals are
+/// ignored since there's no agent to pass them to.
+async fn wait_unless_stopped(
+    delay: Duration,
+    signals: &mut broadcast::Receiver<i32>,
+) -> Option<i32> {
+    let deadline = Instant::now() + delay;
+    loop {
+        tokio::select! {
+            _ = sleep_until(deadline) => return None,
+            signal = next_signal(signals) => {
+                if is_stop_signal(signal) {
+                    return Some(signal);
+                }
             }
         }
     }
+}
+
+async fn next_signal(signals: &mut broadcast::Receiver<i32>) -> i32 {
+    loop {
+        match signals.recv().await {
+            Ok(signal) => return signal,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => pending().await,
+        }
+    }
+}
+
+/// Turns an exit status into the code `bap run` should exit with and a short
+/// description of how the agent stopped. Following shell convention, an agent
+/// killed by a signal maps to 128 + the signal number.
+fn describe_exit(status: ExitStatus) -> (i32, String) {
+    if let Some(code) = status.code() {
+        return (code, format!("exited with code {}", code));
+    }
+
+    match status.signal() {
+        Some(signal) => (
+            128 + signal,
+            format!("was killed by signal {} ({})", signal, signal_name(signal)),
+        ),
+        None => (1, "exited with an unknown status".to_string()),
+    }
+}
 
-    output_handler.await.context("Failed to handle output")?;
+/// Works out which agent version to use, prompting for one if none is set,
+/// and makes sure it's installed. Returns the version and its binary's path.
+pub async fn resolve_agent(specified_version: Option<&str>) -> Result<(String, PathBuf)> {
+    resolve_agent_with_events(specified_version, None).await
+}
+
+async fn resolve_agent_with_events(
+    specified_version: Option<&str>,
+    events: Option<&broadcast::Sender<Event>>,
+) -> Result<(String, PathBuf)> {
+    emit_general(
+        events,
+        None,
+        EventKind::Resolving {
+            requested: specified_version.map(str::to_string),
+        },
+    );
+
+    let version = match specified_version {
+        Some(v) => v.to_string(),
+        None => get_version(events).await?,
+    };
 
-    Ok(())
+    let agent_path = ensure_version_installed(&version, events).await?;
+    Ok((version, agent_path))
 }
 
-async fn get_version() -> Result<String> {
-    if let Some(version) = config::get_version()? {
-        return Ok(version);
+fn emit_general(events: Option<&broadcast::Sender<Event>>, version: Option<&str>, kind: EventKind) {
+    if let Some(events) = events {
+        let _ = events.send(Event::general(version, kind));
+    }
+}
+
+async fn get_version(events: Option<&broadcast::Sender<Event>>) -> Result<String> {
+    if let Some(spec) = config::version_spec()? {
+        if let VersionSpec::Exact(version) = spec {
+            return Ok(version);
+        }
+        let installed = crate::commands::list::list_installed_versions()?;
+        if let Some(version) = spec.best_match(installed.iter().map(String::as_str)) {
+            return Ok(version);
+        }
+
+        // Nothing installed is in range, so offer the newest release that is
+        let releases = GitHubAPI::new()?.get_all_releases().await?;
+        return spec
+            .best_match(releases.iter().map(|release| release.tag_name.as_str()))
+            .ok_or_else(|| anyhow::anyhow!("No Buildkite agent release matches {}", spec));
     }
 
     let versions = crate::commands::list::list_installed_versions()?;
@@ -85,6 +859,7 @@ async fn get_version() -> Result<String> {
 
         if install {
             let version = crate::commands::list_remote::select_version().await?;
+            emit_general(events, Some(&version), EventKind::Installing);
             install::run(&version).await?;
             return Ok(version);
         } else {
@@ -100,7 +875,10 @@ async fn get_version() -> Result<String> {
     Ok(versions[selection].clone())
 }
 
-async fn ensure_version_installed(version: &str) -> Result<PathBuf> {
+async fn ensure_version_installed(
+    version: &str,
+    events: Option<&broadcast::Sender<Event>>,
+) -> Result<PathBuf> {
     let agent_path = bin_dir().join(version).join("buildkite-agent");
 
     if !agent_path.exists() {
@@ -111,6 +889,7 @@ async fn ensure_version_installed(version: &str) -> Result<PathBuf> {
             .interact()?;
 
         if install {
+            emit_general(events, Some(version), EventKind::Installing);
             install::run(version).await?;
         } else {
             anyhow::bail!(
@@ -122,3 +901,45 @@ async fn ensure_version_installed(version: &str) -> Result<PathBuf> {
 
     Ok(agent_path)
 }
+
+pub fn parse_tag(tag: &str) -> Result<String, String> {
+    match tag.split_once('=') {
+        Some((key, _)) if !key.trim().is_empty() => Ok(tag.to_string()),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", tag)),
+    }
+}
+
+/// Combines the tags from the agent's config file with those given on the
+/// command line or a profile. Later tags replace earlier ones with the same
+/// key, so `--tag` and `--queue` win over whatever the config file says.
+fn merge_tags(
+    config_path: &Path,
+    extra_tags: &[String],
+    queue: Option<&str>,
+) -> Result<Vec<String>> {
+    let config_tags = AgentConfig::load_if_exists(config_path)?
+        .and_then(|config| config.get("tags"))
+        .unwrap_or_default();
+
+    let queue_tag = queue.map(|q| format!("queue={}", q));
+    let mut merged: Vec<String> = Vec::new();
+
+    for tag in config_tags
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .chain(extra_tags.iter().map(String::as_str))
+        .chain(queue_tag.as_deref())
+    {
+        let key = tag.split_once('=').map_or(tag, |(k, _)| k);
+        merged.retain(|existing| {
+            existing
+                .split_once('=')
+                .map_or(existing.as_str(), |(k, _)| k)
+                != key
+        });
+        merged.push(tag.to_string());
+    }
+
+    Ok(merged)
+}
@@ -0,0 +1,303 @@
+use crate::commands::run;
+use crate::config;
+use crate::internal::profile::Profile;
+use crate::utils::{bin_dir, logs_dir};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How long bap gets, past the grace timeout, to kill the agent and exit
+/// before the service manager kills everything.
+const STOP_MARGIN_SECS: u64 = 30;
+
+/// The service managers bap can generate definitions for.
+enum ServiceManager {
+    Systemd,
+    Launchd,
+}
+
+impl ServiceManager {
+    fn current() -> Result<Self> {
+        Ok(match std::env::consts::OS {
+            "linux" => ServiceManager::Systemd,
+            "macos" => ServiceManager::Launchd,
+            os => bail!("Services aren't supported on {}", os),
+        })
+    }
+
+    fn definition_path(&self, version: &str) -> PathBuf {
+        let home = dirs::home_dir().unwrap_or_default();
+        match self {
+            ServiceManager::Systemd => home
+                .join(".config/systemd/user")
+                .join(format!("{}.service", unit_name(version))),
+            ServiceManager::Launchd => home
+                .join("Library/LaunchAgents")
+                .join(format!("{}.plist", launchd_label(version))),
+        }
+    }
+}
+
+pub async fn install(
+    version: Option<&str>,
+    profile: Option<&str>,
+    grace_timeout: Option<u64>,
+) -> Result<()> {
+    let version = version.map(|v| v.trim_start_matches('v'));
+    let (version, _) = run::resolve_agent(version).await?;
+    if let Some(profile) = profile {
+        // Catch a missing profile now rather than in the service's log
+        Profile::load(profile)?;
+    }
+    let command = service_command(&version, profile, grace_timeout)?;
+    // Without a grace timeout the agent may take as long as its job does
+    let stop_timeout = grace_timeout.map(|seconds| seconds.saturating_add(STOP_MARGIN_SECS));
+    let manager = ServiceManager::current()?;
+    let path = manager.definition_path(&version);
+
+    let definition = match manager {
+        ServiceManager::Systemd => systemd_unit(&version, &command, stop_timeout),
+        ServiceManager::Launchd => {
+            let log_dir = logs_dir().join(&version);
+            fs::create_dir_all(&log_dir)?;
+            launchd_plist(
+                &version,
+                &command,
+                &log_dir.join("service.log"),
+                stop_timeout,
+            )
+        }
+    };
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    fs::write(&path, definition)
+        .with_context(|| format!("Failed to write service definition: {}", path.display()))?;
+    println!("Wrote {}", path.display());
+
+    match manager {
+        ServiceManager::Systemd => {
+            run_command("systemctl", &["--user", "daemon-reload"])?;
+            run_command(
+                "systemctl",
+                &["--user", "enable", "--now", &unit_name(&version)],
+            )?;
+        }
+        ServiceManager::Launchd => {
+            run_command("launchctl", &["load", "-w", &path.to_string_lossy()])?;
+        }
+    }
+
+    println!("Buildkite agent {} is now running as a service", version);
+    Ok(())
+}
+
+pub fn uninstall(version: Option<&str>) -> Result<()> {
+    let version = resolve_version(version)?;
+    let manager = ServiceManager::current()?;
+    let path = manager.definition_path(&version);
+
+    if !path.exists() {
+        bail!("No service is installed for version {}.", version);
+    }
+
+    match manager {
+        ServiceManager::Systemd => {
+            run_command(
+                "systemctl",
+                &["--user", "disable", "--now", &unit_name(&version)],
+            )?;
+            fs::remove_file(&path)?;
+            run_command("systemctl", &["--user", "daemon-reload"])?;
+        }
+        ServiceManager::Launchd => {
+            run_command("launchctl", &["unload", "-w", &path.to_string_lossy()])?;
+            fs::remove_file(&path)?;
+        }
+    }
+
+    println!("Removed the service for Buildkite agent {}", version);
+    Ok(())
+}
+
+pub fn status(version: Option<&str>) -> Result<()> {
+    let version = resolve_version(version)?;
+    let manager = ServiceManager::current()?;
+
+    if !manager.definition_path(&version).exists() {
+        println!("No service is installed for version {}.", version);
+        return Ok(());
+    }
+
+    // Both commands exit non-zero for a stopped service, which isn't an error here
+    let _ = match manager {
+        ServiceManager::Systemd => Command::new("systemctl")
+            .args(["--user", "status", "--no-pager", &unit_name(&version)])
+            .status(),
+        ServiceManager::Launchd => Command::new("launchctl")
+            .args(["list", &launchd_label(&version)])
+            .status(),
+    }
+    .context("Failed to query the service manager")?;
+
+    Ok(())
+}
+
+/// The service definition that runs `version`, if one has been installed.
+pub fn installed_service(version: &str) -> Option<PathBuf> {
+    let path = ServiceManager::current().ok()?.definition_path(version);
+    path.exists().then_some(path)
+}
+
+fn resolve_version(version: Option<&str>) -> Result<String> {
+    match version {
+        Some(v) => Ok(v.trim_start_matches('v').to_string()),
+        None => config::get_version()?
+            .context("No version specified and no local or default version set."),
+    }
+}
+
+fn unit_name(version: &str) -> String {
+    format!("bap-agent-{}", version)
+}
+
+fn launchd_label(version: &str) -> String {
+    format!("com.bap.agent.{}", version)
+}
+
+/// The command a service runs: `bap run`, so the agent gets its token,
+/// profile, environment and limits just as it would when run by hand.
+fn service_command(
+    version: &str,
+    profile: Option<&str>,
+    grace_timeout: Option<u64>,
+) -> Result<Vec<String>> {
+    let bap = std::env::current_exe().context("Failed to find the bap executable")?;
+    let mut command = vec![
+        bap.display().to_string(),
+        "run".to_string(),
+        version.to_string(),
+    ];
+    if let Some(profile) = profile {
+        command.extend(["--profile".to_string(), profile.to_string()]);
+    }
+    if let Some(seconds) = grace_timeout {
+        command.extend(["--grace-timeout".to_string(), seconds.to_string()]);
+    }
+    Ok(command)
+}
+
+fn systemd_unit(version: &str, command: &[String], stop_timeout: Option<u64>) -> String {
+    format!(
+        r#"# Generated by bap, changes will be lost when the service is reinstalled.
+[Unit]
+Description=Buildkite agent {version} (managed by bap)
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=simple
+ExecStart={command}
+WorkingDirectory={dir}
+Restart=on-failure
+RestartSec=5
+# Only bap gets SIGTERM, and passes it on so the agent can finish its current
+# job before exiting, which systemd waits for rather than killing it after 90s
+KillMode=mixed
+TimeoutStopSec={stop_timeout}
+
+[Install]
+WantedBy=default.target
+"#,
+        version = version,
+        command = command
+            .iter()
+            .map(|arg| systemd_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" "),
+        dir = bin_dir().join(version).display(),
+        stop_timeout = stop_timeout.map_or("infinity".to_string(), |s| s.to_string()),
+    )
+}
+
+/// Quotes an argument for a systemd command line, where `%` starts a
+/// specifier and `$` a variable.
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+fn launchd_plist(
+    version: &str,
+    command: &[String],
+    log_path: &Path,
+    stop_timeout: Option<u64>,
+) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<!-- Generated by bap, changes will be lost when the service is reinstalled. -->
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>{label}</string>
+  <key>ProgramArguments</key>
+  <array>
+{arguments}
+  </array>
+  <key>WorkingDirectory</key>
+  <string>{dir}</string>
+  <key>RunAtLoad</key>
+  <true/>
+  <key>KeepAlive</key>
+  <dict>
+    <key>SuccessfulExit</key>
+    <false/>
+  </dict>
+  <key>ExitTimeOut</key>
+  <integer>{stop_timeout}</integer>
+  <key>StandardOutPath</key>
+  <string>{log}</string>
+  <key>StandardErrorPath</key>
+  <string>{log}</string>
+</dict>
+</plist>
+"#,
+        label = launchd_label(version),
+        arguments = command
+            .iter()
+            .map(|arg| format!("    <string>{}</string>", xml_escape(arg)))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        dir = xml_escape(&bin_dir().join(version).display().to_string()),
+        log = xml_escape(&log_path.display().to_string()),
+        // launchd takes 0 to mean no timeout
+        stop_timeout = stop_timeout.unwrap_or(0),
+    )
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn run_command(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run {}", program))?;
+
+    if !status.success() {
+        bail!("`{} {}` failed ({})", program, args.join(" "), status);
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use crate::commands::run::{self, RunArgs};
+use crate::config::Config;
+use crate::internal::instances::{self, Instance};
+use crate::internal::process::detach;
+use crate::internal::project::ProjectConfig;
+use crate::utils::run_dir;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// How long to watch a freshly started agent for an immediate failure, such
+/// as a bad argument, before handing it off to the background.
+const STARTUP_CHECK: Duration = Duration::from_millis(500);
+
+pub async fn run(args: &RunArgs) -> Result<()> {
+    if args.versions.len() > 1 || args.count > 1 {
+        anyhow::bail!("bap start runs a single agent, start each one you need separately.");
+    }
+
+    let (version, _) = run::resolve_agent(args.versions.first().map(String::as_str)).await?;
+
+    // Checked here, while there's a terminal to ask for a missing token on,
+    // rather than leaving the agent to fail in the background
+    let layers = run::Layers::load(args.profile.as_deref(), args.token.as_deref())?;
+    let settings = layers.settings(&Config::load()?, &version);
+    layers.check_env(&settings)?;
+    run::preflight(&version, &settings, true)?;
+
+    fs::create_dir_all(run_dir())
+        .with_context(|| format!("Failed to create directory: {}", run_dir().display()))?;
+
+    let id = instances::next_id()?;
+    let log = instances::log_path(&id);
+    // Everything after `bap start` is handed to `bap run` untouched, with the
+    // resolved version put in front if one wasn't given.
+    let mut run_args: Vec<OsString> = std::env::args_os().skip(2).collect();
+    if args.versions.is_empty() {
+        run_args.insert(0, version.clone().into());
+    }
+
+    let mut run_command: Vec<OsString> = vec!["run".into()];
+    run_command.extend(run_args.iter().cloned());
+    let mut child = spawn_detached(&run_command, &log)?;
+
+    let profile = match &args.profile {
+        Some(profile) => Some(profile.clone()),
+        None => ProjectConfig::load()?.and_then(|project| project.profile),
+    };
+
+    let instance = Instance {
+        id: id.clone(),
+        version: version.clone(),
+        pid: child.id(),
+        started_at: Utc::now().timestamp(),
+        cwd: std::env::current_dir()?,
+        log: log.clone(),
+        args: run_args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+        profile,
+    };
+    instance.save()?;
+
+    tokio::time::sleep(STARTUP_CHECK).await;
+    if let Some(status) = child.try_wait()? {
+        instance.remove()?;
+        anyhow::bail!(
+            "Buildkite agent {} exited straight away ({}), see {} for details",
+            version,
+            status,
+            log.display()
+        );
+    }
+
+    println!(
+        "Started Buildkite agent {} in the background (id {}, pid {})",
+        version, id, instance.pid
+    );
+    println!("Logs: {}", log.display());
+    Ok(())
+}
+
+/// Starts bap again with `args`, in a new session of its own so it keeps going
+/// after this terminal is closed, with everything it prints going to `log`.
+pub fn spawn_detached(args: &[OsString], log: &Path) -> Result<Child> {
+    let log_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(log)
+        .with_context(|| format!("Failed to open log file: {}", log.display()))?;
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(log_file.try_clone()?)
+        .stderr(log_file);
+
+    detach(&mut command)
+        .spawn()
+        .context("Failed to start bap in the background")
+}
@@ -0,0 +1,69 @@
+use crate::internal::instances::{self, Instance};
+use crate::internal::signals::send_signal;
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+pub async fn run(target: Option<&str>, all: bool, cancel: bool, timeout: u64) -> Result<()> {
+    let running = instances::list()?;
+
+    let selected: Vec<Instance> = match target {
+        _ if all => running,
+        Some(target) => running
+            .into_iter()
+            .filter(|i| i.id == target || i.version == target.trim_start_matches('v'))
+            .collect(),
+        None => anyhow::bail!("Specify an agent id or version to stop, or use --all."),
+    };
+
+    if selected.is_empty() {
+        match target {
+            Some(target) if !all => anyhow::bail!("No background agent matches {}.", target),
+            _ => {
+                println!("No background Buildkite agents running.");
+                return Ok(());
+            }
+        }
+    }
+
+    for instance in &selected {
+        // The supervising `bap run` passes these on to the agent: the first
+        // lets its current job finish, the second cancels it.
+        send_signal(instance.pid, libc::SIGTERM)?;
+        if cancel {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            send_signal(instance.pid, libc::SIGTERM)?;
+        }
+        println!(
+            "Stopping Buildkite agent {} (id {}, pid {})...",
+            instance.version, instance.id, instance.pid
+        );
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let mut remaining = selected;
+    while !remaining.is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let (stopped, still_running): (Vec<_>, Vec<_>) =
+            remaining.into_iter().partition(|i| !i.is_running());
+        for instance in stopped {
+            instance.remove()?;
+            println!(
+                "Stopped Buildkite agent {} (id {})",
+                instance.version, instance.id
+            );
+        }
+        remaining = still_running;
+    }
+
+    for instance in remaining {
+        println!(
+            "Buildkite agent {} (id {}) is still finishing its current job, run `bap stop {} --cancel` to cancel it",
+            instance.version, instance.id, instance.id
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,504 @@
+use crate::internal::agent_log::{self, LogEvent};
+use crate::internal::control::{default_socket_path, AgentStatus, Client, Request};
+use crate::internal::instances::{self, Instance};
+use crate::internal::process::{child_of, cpu_seconds, detach, resident_bytes};
+use crate::internal::signals::send_signal;
+use crate::utils::format_duration;
+use anyhow::Result;
+use chrono::Utc;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the dashboard reloads what it shows.
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// How many lines of each agent's output to keep for the log pane.
+const LOG_LINES: usize = 500;
+
+/// How much of the end of a background agent's log file to read, which is
+/// plenty to find the last `LOG_LINES` lines.
+const LOG_TAIL_BYTES: u64 = 64 * 1024;
+
+/// Where an agent on the dashboard is managed from.
+enum Source {
+    /// Started with `bap start`, and found through its pidfile.
+    Background(Instance),
+    /// Run by the bap daemon.
+    Daemon(AgentStatus),
+}
+
+struct AgentRow {
+    source: Source,
+    id: String,
+    version: String,
+    state: String,
+    job: Option<String>,
+    uptime: Duration,
+    /// The buildkite-agent process itself, not the bap supervising it.
+    pid: Option<u32>,
+    cpu_percent: Option<f64>,
+    memory: Option<u64>,
+    lines: Vec<String>,
+}
+
+impl AgentRow {
+    /// Identifies the agent across refreshes.
+    fn key(&self) -> String {
+        match self.source {
+            Source::Background(_) => format!("start:{}", self.id),
+            Source::Daemon(_) => format!("daemon:{}", self.id),
+        }
+    }
+}
+
+struct Dashboard {
+    socket: PathBuf,
+    agents: Vec<AgentRow>,
+    table: TableState,
+    /// Lines scrolled back from the end of the log pane, 0 when following.
+    scroll: usize,
+    /// The last CPU time seen for each agent, to work out its usage since.
+    cpu_samples: HashMap<String, (f64, Instant)>,
+    message: Option<String>,
+    /// What the daemon last said it's running.
+    daemon_agents: Vec<DaemonAgent>,
+    daemon_updates: Receiver<Vec<DaemonAgent>>,
+    /// How stop and restart requests went, as they finish.
+    outcomes: Sender<String>,
+    outcome_updates: Receiver<String>,
+}
+
+/// An agent the daemon is running, with its recent output.
+type DaemonAgent = (AgentStatus, Vec<String>);
+
+pub fn run() -> Result<()> {
+    let socket = default_socket_path();
+    let (daemon_sender, daemon_updates) = mpsc::channel();
+    // The daemon can take a while to answer, so it's asked away from the UI
+    thread::spawn({
+        let socket = socket.clone();
+        move || poll_daemon(&socket, daemon_sender)
+    });
+    let (outcomes, outcome_updates) = mpsc::channel();
+
+    let mut dashboard = Dashboard {
+        socket,
+        agents: Vec::new(),
+        table: TableState::default().with_selected(0),
+        scroll: 0,
+        cpu_samples: HashMap::new(),
+        message: None,
+        daemon_agents: Vec::new(),
+        daemon_updates,
+        outcomes,
+        outcome_updates,
+    };
+    dashboard.refresh()?;
+
+    let mut terminal = ratatui::try_init()?;
+    let result = dashboard.run(&mut terminal);
+    ratatui::try_restore()?;
+    result
+}
+
+impl Dashboard {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        let mut refreshed = Instant::now();
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let timeout = REFRESH.saturating_sub(refreshed.elapsed());
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    match key.code {
+                        KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            return Ok(())
+                        }
+                        KeyCode::Up | KeyCode::Char('k') => self.select(-1),
+                        KeyCode::Down | KeyCode::Char('j') => self.select(1),
+                        KeyCode::PageUp => self.scroll += 10,
+                        KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+                        KeyCode::Char('t') | KeyCode::Enter => self.scroll = 0,
+                        KeyCode::Char('s') => self.stop_selected(),
+                        KeyCode::Char('r') => self.restart_selected(),
+                        _ => {}
+                    }
+                }
+            }
+
+            if let Some(message) = self.outcome_updates.try_iter().last() {
+                self.message = Some(message);
+            }
+            if refreshed.elapsed() >= REFRESH {
+                self.refresh()?;
+                refreshed = Instant::now();
+            }
+        }
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let selected = self.selected().map(AgentRow::key);
+
+        if let Some(daemon_agents) = self.daemon_updates.try_iter().last() {
+            self.daemon_agents = daemon_agents;
+        }
+        let mut agents: Vec<AgentRow> =
+            instances::list()?.into_iter().map(background_row).collect();
+        agents.extend(self.daemon_agents.iter().cloned().map(daemon_row));
+
+        let now = Instant::now();
+        for agent in &mut agents {
+            let Some(cpu) = agent.pid.and_then(cpu_seconds) else {
+                continue;
+            };
+            if let Some((last_cpu, at)) = self.cpu_samples.get(&agent.key()) {
+                let elapsed = now.duration_since(*at).as_secs_f64();
+                if elapsed > 0.0 {
+                    agent.cpu_percent = Some((cpu - last_cpu).max(0.0) / elapsed * 100.0);
+                }
+            }
+            self.cpu_samples.insert(agent.key(), (cpu, now));
+        }
+
+        self.agents = agents;
+        let index = selected
+            .and_then(|key| self.agents.iter().position(|a| a.key() == key))
+            .unwrap_or(0);
+        self.table
+            .select(Some(index.min(self.agents.len().saturating_sub(1))));
+        Ok(())
+    }
+
+    fn selected(&self) -> Option<&AgentRow> {
+        self.table.selected().and_then(|i| self.agents.get(i))
+    }
+
+    fn select(&mut self, offset: isize) {
+        if self.agents.is_empty() {
+            return;
+        }
+        let current = self.table.selected().unwrap_or(0) as isize;
+        let last = self.agents.len() as isize - 1;
+        self.table
+            .select(Some((current + offset).clamp(0, last) as usize));
+        self.scroll = 0;
+    }
+
+    fn stop_selected(&mut self) {
+        let Some(agent) = self.selected() else {
+            return;
+        };
+
+        let id = agent.id.clone();
+        let socket = self.socket.clone();
+        let stop: Box<dyn FnOnce() -> Result<()> + Send> = match &agent.source {
+            // The supervising `bap run` lets the current job finish first
+            Source::Background(instance) => {
+                let pid = instance.pid;
+                Box::new(move || send_signal(pid, libc::SIGTERM))
+            }
+            Source::Daemon(status) => {
+                let request = Request::Stop {
+                    id: status.id.clone(),
+                    cancel: false,
+                };
+                Box::new(move || send(&socket, &request))
+            }
+        };
+        self.in_background(stop, move |result| match result {
+            Ok(()) => format!("Stopping agent {} once its current job finishes", id),
+            Err(e) => format!("Failed to stop agent {}: {:#}", id, e),
+        });
+    }
+
+    fn restart_selected(&mut self) {
+        let Some(agent) = self.selected() else {
+            return;
+        };
+
+        let id = agent.id.clone();
+        let socket = self.socket.clone();
+        let restart: Box<dyn FnOnce() -> Result<()> + Send> = match &agent.source {
+            Source::Background(instance) => {
+                let instance = instance.clone();
+                Box::new(move || restart_background(&instance))
+            }
+            Source::Daemon(status) => {
+                let request = Request::Restart {
+                    id: status.id.clone(),
+                };
+                Box::new(move || send(&socket, &request))
+            }
+        };
+        self.in_background(restart, move |result| match result {
+            Ok(()) => format!("Restarting agent {} once its current job finishes", id),
+            Err(e) => format!("Failed to restart agent {}: {:#}", id, e),
+        });
+    }
+
+    /// Runs `action` on a thread of its own so the dashboard keeps updating,
+    /// showing what `describe` makes of how it went once it's done.
+    fn in_background(
+        &self,
+        action: Box<dyn FnOnce() -> Result<()> + Send>,
+        describe: impl FnOnce(Result<()>) -> String + Send + 'static,
+    ) {
+        let outcomes = self.outcomes.clone();
+        thread::spawn(move || {
+            let _ = outcomes.send(describe(action()));
+        });
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let table_height = self.agents.len().max(1) as u16 + 3;
+        let [table_area, log_area, footer_area] = Layout::vertical([
+            Constraint::Length(table_height.min(frame.area().height / 2)),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let header = Row::new([
+            "ID", "FROM", "VERSION", "STATE", "JOB", "UPTIME", "CPU", "MEM",
+        ])
+        .style(Style::new().add_modifier(Modifier::BOLD));
+        let rows = self.agents.iter().map(|agent| {
+            Row::new([
+                Cell::from(agent.id.clone()),
+                Cell::from(match agent.source {
+                    Source::Background(_) => "start",
+                    Source::Daemon(_) => "daemon",
+                }),
+                Cell::from(agent.version.clone()),
+                Cell::from(agent.state.clone()),
+                Cell::from(agent.job.clone().unwrap_or_else(|| "-".to_string())),
+                Cell::from(format_duration(agent.uptime)),
+                Cell::from(
+                    agent
+                        .cpu_percent
+                        .map(|cpu| format!("{:.1}%", cpu))
+                        .unwrap_or_else(|| "-".to_string()),
+                ),
+                Cell::from(
+                    agent
+                        .memory
+                        .map(|bytes| format!("{}M", bytes / 1024 / 1024))
+                        .unwrap_or_else(|| "-".to_string()),
+                ),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(4),
+                Constraint::Length(6),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Min(20),
+                Constraint::Length(8),
+                Constraint::Length(7),
+                Constraint::Length(7),
+            ],
+        )
+        .header(header)
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(" bap agents "));
+        frame.render_stateful_widget(table, table_area, &mut self.table);
+
+        let height = log_area.height.saturating_sub(2) as usize;
+        let total = self.selected().map_or(0, |a| a.lines.len());
+        self.scroll = self.scroll.min(total.saturating_sub(height));
+        let lines = self.selected().map(|a| a.lines.as_slice()).unwrap_or(&[]);
+        let end = lines.len() - self.scroll;
+        let visible: Vec<Line> = lines[end.saturating_sub(height)..end]
+            .iter()
+            .map(|line| Line::raw(line.as_str()))
+            .collect();
+        let title = match self.selected() {
+            Some(agent) => {
+                let from = match agent.source {
+                    Source::Background(_) => "start",
+                    Source::Daemon(_) => "daemon",
+                };
+                let mode = if self.scroll == 0 {
+                    "following"
+                } else {
+                    "paused, t to follow"
+                };
+                format!(" {} agent {} ({}) ", from, agent.id, mode)
+            }
+            None => " no agents running ".to_string(),
+        };
+        frame.render_widget(
+            Paragraph::new(visible).block(Block::bordered().title(title)),
+            log_area,
+        );
+
+        let footer = self.message.clone().unwrap_or_else(|| {
+            "↑/↓ select  s stop  r restart  t tail  PgUp/PgDn scroll  q quit".to_string()
+        });
+        frame.render_widget(Paragraph::new(footer), footer_area);
+    }
+}
+
+fn background_row(instance: Instance) -> AgentRow {
+    let lines = tail_lines(&instance);
+    let pid = child_of(instance.pid);
+    let (state, job) = job_state(&lines);
+    AgentRow {
+        id: instance.id.clone(),
+        version: instance.version.clone(),
+        state: state.to_string(),
+        job,
+        uptime: instance.uptime(),
+        pid,
+        cpu_percent: None,
+        memory: pid.and_then(resident_bytes),
+        lines,
+        source: Source::Background(instance),
+    }
+}
+
+fn daemon_row((status, lines): DaemonAgent) -> AgentRow {
+    let (activity, job) = job_state(&lines);
+    let state = match status.state.as_str() {
+        "running" => activity.to_string(),
+        other => other.to_string(),
+    };
+    let started = Utc::now().timestamp() - status.started_at;
+
+    AgentRow {
+        id: status.id.clone(),
+        version: status.version.clone(),
+        state,
+        job,
+        uptime: Duration::from_secs(started.max(0) as u64),
+        pid: status.pid,
+        cpu_percent: None,
+        memory: status.pid.and_then(resident_bytes),
+        lines,
+        source: Source::Daemon(status),
+    }
+}
+
+/// Asks the daemon what it's running every `REFRESH`, until the dashboard
+/// stops listening.
+fn poll_daemon(socket: &Path, updates: Sender<Vec<DaemonAgent>>) {
+    loop {
+        // No daemon running just means there's nothing more to show
+        let agents = Client::connect(socket)
+            .and_then(|mut client| daemon_agents(&mut client))
+            .unwrap_or_default();
+        if updates.send(agents).is_err() {
+            return;
+        }
+        thread::sleep(REFRESH);
+    }
+}
+
+fn daemon_agents(client: &mut Client) -> Result<Vec<DaemonAgent>> {
+    let reply = client.request(&Request::List)?;
+    let statuses: Vec<AgentStatus> = serde_json::from_value(reply["agents"].clone())?;
+
+    let mut agents = Vec::new();
+    for status in statuses {
+        let reply = client.request(&Request::Logs {
+            id: status.id.clone(),
+            lines: LOG_LINES,
+        })?;
+        let lines: Vec<String> = serde_json::from_value(reply["lines"].clone())?;
+        agents.push((status, lines));
+    }
+    Ok(agents)
+}
+
+fn send(socket: &Path, request: &Request) -> Result<()> {
+    Client::connect(socket)?.request(request)?;
+    Ok(())
+}
+
+/// Works out from an agent's output whether it's running a job, and which.
+fn job_state(lines: &[String]) -> (&'static str, Option<String>) {
+    let mut current = None;
+    for line in lines {
+        match agent_log::parse_line(line) {
+            Some(LogEvent::JobStarted { job, .. }) => current = Some(job),
+            Some(LogEvent::JobFinished { job, .. }) if current.as_ref() == Some(&job) => {
+                current = None
+            }
+            _ => {}
+        }
+    }
+
+    match current {
+        Some(job) => ("busy", Some(job)),
+        None => ("idle", None),
+    }
+}
+
+/// The last lines of a background agent's log file.
+fn tail_lines(instance: &Instance) -> Vec<String> {
+    let Ok(mut file) = File::open(&instance.log) else {
+        return Vec::new();
+    };
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let start = size.saturating_sub(LOG_TAIL_BYTES);
+    let mut content = Vec::new();
+    if file.seek(SeekFrom::Start(start)).is_err() || file.read_to_end(&mut content).is_err() {
+        return Vec::new();
+    }
+
+    let content = String::from_utf8_lossy(&content);
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    // Reading from part way through the file most likely started mid-line
+    if start > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+    let skip = lines.len().saturating_sub(LOG_LINES);
+    lines.split_off(skip)
+}
+
+/// Waits for the process given as the first argument to exit, then runs the
+/// rest of the arguments as a command.
+const WAIT_THEN_RUN: &str =
+    r#"pid=$1; shift; while kill -0 "$pid" 2>/dev/null; do sleep 1; done; exec "$@""#;
+
+/// Stops a background agent and has it started again with `bap start`, from
+/// the same directory with the same arguments, once it has gone. The waiting
+/// is left to a shell in a session of its own, so the dashboard carries on
+/// updating and the restart still happens if it's closed in the meantime.
+fn restart_background(instance: &Instance) -> Result<()> {
+    send_signal(instance.pid, libc::SIGTERM)?;
+
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(WAIT_THEN_RUN)
+        .arg("sh")
+        .arg(instance.pid.to_string())
+        .arg(std::env::current_exe()?)
+        .arg("start")
+        .args(&instance.args)
+        .current_dir(&instance.cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    detach(&mut command).spawn()?;
+    Ok(())
+}
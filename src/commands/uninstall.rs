@@ -1,3 +1,4 @@
+use crate::commands::service;
 use crate::config::Config;
 use crate::utils::{bin_dir, versions_dir};
 use anyhow::{Context, Result};
@@ -12,6 +13,16 @@ pub fn run(version: &str) -> Result<()> {
         anyhow::bail!("Version {} is not installed.", version);
     }
 
+    // A service pointing at a removed binary will fail to start, so let the user know
+    if let Some(path) = service::installed_service(version) {
+        println!(
+            "Warning: the service at {} still runs version {}. Remove it with `bap service uninstall {}`.",
+            path.display(),
+            version,
+            version
+        );
+    }
+
     // Remove the version directory
     // This seems like the "simplest" way
     fs::remove_dir_all(&version_dir).context(format!(
@@ -1,11 +1,43 @@
-use crate::config;
+use crate::config::LocalConfig;
+use crate::internal::project::{self, PROJECT_FILE};
+use crate::internal::token;
 use anyhow::Result;
+use std::path::Path;
 
-pub fn run(version: &str) -> Result<()> {
-    config::set_local_version(version)?;
-    println!(
-        "Local Buildkite agent version set to {} for this directory",
-        version
-    );
+pub fn run(version: Option<&str>, token_name: Option<&str>) -> Result<()> {
+    if let Some(name) = token_name {
+        // Catch a typo now rather than when the agent fails to start
+        token::existing_named_token_path(name)?;
+    }
+
+    // A project's .bap.toml takes the settings if it has one, as that's what
+    // bap reads first
+    if Path::new(PROJECT_FILE).exists() {
+        if let Some(version) = version {
+            project::set_value("version", version)?;
+        }
+        if let Some(name) = token_name {
+            project::set_value("token", name)?;
+        }
+    } else {
+        let mut local = LocalConfig::load_baprc()?;
+        if let Some(version) = version {
+            local.version = Some(version.to_string());
+        }
+        if let Some(name) = token_name {
+            local.token = Some(name.to_string());
+        }
+        local.save()?;
+    }
+
+    if let Some(version) = version {
+        println!(
+            "Local Buildkite agent version set to {} for this directory",
+            version
+        );
+    }
+    if let Some(name) = token_name {
+        println!("Agents run in this directory will use the {} token", name);
+    }
     Ok(())
 }
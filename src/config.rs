@@ -1,23 +1,237 @@
+use crate::commands::list::list_installed_versions;
+use crate::internal::project::{ProjectConfig, VersionSpec};
 use crate::utils::bap_root;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
 
 #[derive(Deserialize, Serialize, Default)]
 pub struct Config {
     pub default_version: Option<String>,
+    #[serde(default)]
+    pub logs: LogSettings,
+    /// How agents are started, for every version unless overridden below.
+    #[serde(default)]
+    pub agent: AgentSettings,
+    /// Settings for particular versions, layered over `agent`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub versions: BTreeMap<String, AgentSettings>,
+    /// Where to send word of agents crashing, giving up or stopping.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notifications: Vec<NotificationHook>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LogSettings {
+    /// Rotate once the current log file grows past this many megabytes.
+    pub max_size_mb: u64,
+    /// Rotate once the current log file is older than this many hours.
+    pub max_age_hours: u64,
+    /// How many rotated files to keep alongside the current one.
+    pub retain: usize,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            max_size_mb: 10,
+            max_age_hours: 24,
+            retain: 5,
+        }
+    }
+}
+
+/// Somewhere to tell about agent lifecycle events: a URL to POST JSON to, or a
+/// command to run with the JSON on its standard input.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct NotificationHook {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Which events to notify about, all of them by default.
+    #[serde(default = "NotifyOn::all")]
+    pub on: Vec<NotifyOn>,
+    /// How many more times to try a notification that fails.
+    #[serde(default = "default_notification_retries")]
+    pub retries: u32,
+    /// The most notifications to send in any hour, anything more is dropped.
+    #[serde(default = "default_notifications_per_hour")]
+    pub max_per_hour: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyOn {
+    /// The agent exited with a failure without being asked to.
+    Crashed,
+    /// The agent restarted too often and won't be started again.
+    GaveUp,
+    /// bap has stopped supervising an agent that exited cleanly or was asked to stop.
+    Stopped,
+}
+
+impl NotifyOn {
+    fn all() -> Vec<NotifyOn> {
+        vec![NotifyOn::Crashed, NotifyOn::GaveUp, NotifyOn::Stopped]
+    }
+}
+
+fn default_notification_retries() -> u32 {
+    3
+}
+
+fn default_notifications_per_hour() -> usize {
+    10
+}
+
+/// The environment and limits an agent process is started with.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct AgentSettings {
+    /// Only pass these variables from bap's environment on to the agent, plus
+    /// `PATH` and `HOME`. A trailing `*` matches any suffix, e.g. `BUILDKITE_*`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_allow: Option<Vec<String>>,
+    /// Never pass these variables on, even if they're allowed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env_deny: Vec<String>,
+    /// Extra variables to set for the agent.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// The directory to start the agent in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<PathBuf>,
+    /// The niceness to run the agent with, from -20 to 19.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+    #[serde(skip_serializing_if = "ResourceLimits::is_empty")]
+    pub limits: ResourceLimits,
+    /// Where to get the agent token when it's started, in place of the one in
+    /// its config file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<TokenProvider>,
+}
+
+/// Somewhere to get an agent token from each time an agent starts. The token
+/// reaches the agent through its environment and is never written to disk.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenProvider {
+    /// An environment variable of bap's to read it from.
+    Env(String),
+    /// A file holding nothing but the token.
+    File(PathBuf),
+    /// A command that prints the token, e.g. `op read op://ci/buildkite/token`.
+    Command(String),
+    /// A token saved with `bap auth --name`.
+    Named(String),
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// The most files the agent and each of its jobs can have open.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_files: Option<u64>,
+    /// The most virtual memory, in megabytes, each process can map.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address_space_mb: Option<u64>,
+    /// The most CPU time, in seconds, each process can use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.open_files.is_none() && self.address_space_mb.is_none() && self.cpu_seconds.is_none()
+    }
+
+    /// The address space limit in bytes, failing if it's too large to count.
+    pub fn address_space_bytes(&self) -> Result<Option<u64>> {
+        self.address_space_mb
+            .map(|mb| {
+                mb.checked_mul(1024 * 1024)
+                    .with_context(|| format!("address_space_mb of {} is too large", mb))
+            })
+            .transpose()
+    }
+}
+
+impl AgentSettings {
+    pub fn is_empty(&self) -> bool {
+        self.env_allow.is_none()
+            && self.env_deny.is_empty()
+            && self.env.is_empty()
+            && self.working_dir.is_none()
+            && self.nice.is_none()
+            && self.limits.is_empty()
+            && self.token.is_none()
+    }
+
+    /// Fails on settings the agent couldn't be started with, so they're caught
+    /// when they're loaded rather than once the agent process is forked.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(nice) = self.nice.filter(|nice| !(-20..=19).contains(nice)) {
+            anyhow::bail!("nice must be from -20 to 19, got {}", nice);
+        }
+        self.limits.address_space_bytes()?;
+        Ok(())
+    }
+
+    /// These settings with `other` layered on top: anything `other` sets wins,
+    /// extra variables are merged and denied variables add up.
+    pub fn overlay(&self, other: &AgentSettings) -> AgentSettings {
+        let mut env = self.env.clone();
+        env.extend(other.env.clone());
+
+        AgentSettings {
+            env_allow: other.env_allow.clone().or_else(|| self.env_allow.clone()),
+            env_deny: [self.env_deny.clone(), other.env_deny.clone()].concat(),
+            env,
+            working_dir: other
+                .working_dir
+                .clone()
+                .or_else(|| self.working_dir.clone()),
+            nice: other.nice.or(self.nice),
+            limits: ResourceLimits {
+                open_files: other.limits.open_files.or(self.limits.open_files),
+                address_space_mb: other
+                    .limits
+                    .address_space_mb
+                    .or(self.limits.address_space_mb),
+                cpu_seconds: other.limits.cpu_seconds.or(self.limits.cpu_seconds),
+            },
+            token: other.token.clone().or_else(|| self.token.clone()),
+        }
+    }
 }
 
 impl Config {
+    /// The settings to start `version` with.
+    pub fn agent_settings(&self, version: &str) -> AgentSettings {
+        match self.versions.get(version) {
+            Some(settings) => self.agent.overlay(settings),
+            None => self.agent.clone(),
+        }
+    }
+
     pub fn load() -> Result<Self> {
         let config_path = config_file_path();
-        if config_path.exists() {
-            let content = fs::read_to_string(config_path)?;
-            Ok(serde_json::from_str(&content)?)
-        } else {
-            Ok(Config::default())
+        if !config_path.exists() {
+            return Ok(Config::default());
+        }
+        let content = fs::read_to_string(&config_path)?;
+        let config: Config = serde_json::from_str(&content)?;
+        let invalid = || format!("Invalid agent settings in {}", config_path.display());
+        config.agent.validate().with_context(invalid)?;
+        for settings in config.versions.values() {
+            settings.validate().with_context(invalid)?;
         }
+        Ok(config)
     }
 
     pub fn save(&self) -> Result<()> {
@@ -28,18 +242,88 @@ impl Config {
     }
 }
 
-pub fn set_local_version(version: &str) -> Result<()> {
-    fs::write(".baprc", version)?;
-    Ok(())
+/// What the current directory asks for. That's what its `.bap.toml` says,
+/// with `.baprc` filling in anything it doesn't. A `.baprc` used to hold
+/// nothing but a version, which still works, and can also pick a named token
+/// with a `token=NAME` line.
+#[derive(Default)]
+pub struct LocalConfig {
+    pub version: Option<String>,
+    pub token: Option<String>,
 }
 
-pub fn get_version() -> Result<Option<String>> {
-    if let Ok(version) = fs::read_to_string(".baprc") {
-        return Ok(Some(version.trim().to_string()));
+impl LocalConfig {
+    pub fn load() -> Result<Self> {
+        let mut local = Self::load_baprc()?;
+        if let Some(project) = ProjectConfig::load()? {
+            local.version = project.version.or(local.version);
+            local.token = project.token.or(local.token);
+        }
+        Ok(local)
     }
 
-    let config = Config::load()?;
-    Ok(config.default_version)
+    /// The `.baprc` in the current directory alone, empty if there isn't one.
+    pub fn load_baprc() -> Result<Self> {
+        let Ok(content) = fs::read_to_string(".baprc") else {
+            return Ok(Self::default());
+        };
+
+        let mut local = Self::default();
+        for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+            {
+                Some(("version", value)) => local.version = Some(value.to_string()),
+                Some(("token", value)) => local.token = Some(value.to_string()),
+                Some((key, _)) => anyhow::bail!("Unknown setting in .baprc: {}", key),
+                None => local.version = Some(line.to_string()),
+            }
+        }
+        Ok(local)
+    }
+
+    /// Writes the settings to `.baprc`.
+    pub fn save(&self) -> Result<()> {
+        let mut content = String::new();
+        if let Some(version) = &self.version {
+            content.push_str(version);
+            content.push('\n');
+        }
+        if let Some(token) = &self.token {
+            content.push_str(&format!("token={}\n", token));
+        }
+        fs::write(".baprc", content)?;
+        Ok(())
+    }
+}
+
+/// The version the current directory asks for, or otherwise the default.
+pub fn version_spec() -> Result<Option<VersionSpec>> {
+    let version = match LocalConfig::load()?.version {
+        Some(version) => Some(version),
+        None => Config::load()?.default_version,
+    };
+    Ok(version.as_deref().map(VersionSpec::parse))
+}
+
+/// The version to use here, with a range narrowed down to the newest
+/// installed version in it.
+pub fn get_version() -> Result<Option<String>> {
+    match version_spec()? {
+        Some(VersionSpec::Exact(version)) => Ok(Some(version)),
+        Some(spec) => {
+            let installed = list_installed_versions()?;
+            match spec.best_match(installed.iter().map(String::as_str)) {
+                Some(version) => Ok(Some(version)),
+                None => anyhow::bail!(
+                    "No installed Buildkite agent version matches {}. Install one with `bap install <version>`",
+                    spec
+                ),
+            }
+        }
+        None => Ok(None),
+    }
 }
 
 fn config_file_path() -> PathBuf {
@@ -0,0 +1,289 @@
+use crate::utils::bin_dir;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A `buildkite-agent.cfg`, kept line by line so that changing a setting
+/// leaves its comments, ordering and formatting alone.
+///
+/// Settings are `key=value` lines, with the value optionally in double quotes
+/// and `\"` and `\\` escaped inside them. Lines starting with `#` are comments,
+/// and a key set more than once takes the last value, like the agent does.
+pub struct AgentConfig {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl AgentConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration file: {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            lines: content.lines().map(str::to_string).collect(),
+        })
+    }
+
+    /// The config file of an installed agent version.
+    pub fn for_version(version: &str) -> Result<Self> {
+        let path = config_path(version);
+        if !path.exists() {
+            anyhow::bail!(
+                "Configuration file not found for version {}. Is this version installed?",
+                version
+            );
+        }
+        Self::load(&path)
+    }
+
+    /// The config file at `path`, or `None` if there isn't one.
+    pub fn load_if_exists(path: &Path) -> Result<Option<Self>> {
+        if path.exists() {
+            Self::load(path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Every setting in the file, in order, including any set more than once.
+    pub fn settings(&self) -> impl Iterator<Item = (&str, String)> {
+        self.lines.iter().filter_map(|line| parse_setting(line))
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.lines
+            .iter()
+            .rev()
+            .filter_map(|line| parse_setting(line))
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Sets `key`, replacing the line it's currently set on. A key that isn't
+    /// set yet goes just after a commented out example of it if there is one,
+    /// as the default config has for most keys, or otherwise at the end.
+    pub fn set(&mut self, key: &str, value: &str) {
+        let line = format!("{}={}", key, quote(value));
+
+        if let Some(index) = self.setting_lines(key).last().copied() {
+            let indent = leading_whitespace(&self.lines[index]).to_string();
+            self.lines[index] = format!("{}{}", indent, line);
+            return;
+        }
+
+        let example = self.lines.iter().rposition(|existing| {
+            existing
+                .trim_start()
+                .strip_prefix('#')
+                .and_then(parse_setting)
+                .is_some_and(|(k, _)| k == key)
+        });
+        match example {
+            Some(index) => self.lines.insert(index + 1, line),
+            None => self.lines.push(line),
+        }
+    }
+
+    /// Removes every line setting `key`. Returns whether there were any.
+    pub fn unset(&mut self, key: &str) -> bool {
+        let before = self.lines.len();
+        self.lines
+            .retain(|line| parse_setting(line).is_none_or(|(k, _)| k != key));
+        self.lines.len() != before
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut content = self.lines.join("\n");
+        content.push('\n');
+        fs::write(&self.path, content).with_context(|| {
+            format!(
+                "Failed to write configuration file: {}",
+                self.path.display()
+            )
+        })
+    }
+
+    fn setting_lines(&self, key: &str) -> Vec<usize> {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| parse_setting(line).is_some_and(|(k, _)| k == key))
+            .map(|(index, _)| index)
+            .collect()
+    }
+}
+
+pub fn config_path(version: &str) -> PathBuf {
+    bin_dir().join(version).join("buildkite-agent.cfg")
+}
+
+/// Whether `key` looks like a setting the agent could have, e.g. `build-path`.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+fn parse_setting(line: &str) -> Option<(&str, String)> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    if !is_valid_key(key) {
+        return None;
+    }
+    Some((key, unquote(value.trim())))
+}
+
+fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+fn unquote(value: &str) -> String {
+    let Some(inner) = value
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+    else {
+        return value.to_string();
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => unquoted.push('\n'),
+                Some('t') => unquoted.push('\t'),
+                Some(escaped) => unquoted.push(escaped),
+                None => unquoted.push('\\'),
+            },
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(content: &str) -> AgentConfig {
+        AgentConfig {
+            path: PathBuf::from("buildkite-agent.cfg"),
+            lines: content.lines().map(str::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn quotes_values_with_quotes_and_hashes() {
+        let mut config = config("");
+        config.set("tags", r#"queue="deploy" # not a comment"#);
+        config.set("build-path", r"C:\builds");
+
+        assert_eq!(
+            config.lines,
+            [
+                r#"tags="queue=\"deploy\" # not a comment""#,
+                r#"build-path="C:\\builds""#,
+            ]
+        );
+        assert_eq!(
+            config.get("tags").as_deref(),
+            Some(r#"queue="deploy" # not a comment"#)
+        );
+        assert_eq!(config.get("build-path").as_deref(), Some(r"C:\builds"));
+    }
+
+    #[test]
+    fn reads_unquoted_and_quoted_values() {
+        let config = config("name=my-agent\ntags = \"a=b,c=d\"\n# token=\"commented\"");
+
+        assert_eq!(config.get("name").as_deref(), Some("my-agent"));
+        assert_eq!(config.get("tags").as_deref(), Some("a=b,c=d"));
+        assert_eq!(config.get("token"), None);
+    }
+
+    #[test]
+    fn sets_a_key_after_its_commented_out_example() {
+        let mut config = config(
+            "token=\"xxx\"\n\n# The path to the hooks\n# hooks-path=\"/etc/hooks\"\n\n# debug=true",
+        );
+        config.set("hooks-path", "/srv/hooks");
+
+        assert_eq!(
+            config.lines,
+            [
+                "token=\"xxx\"",
+                "",
+                "# The path to the hooks",
+                "# hooks-path=\"/etc/hooks\"",
+                "hooks-path=\"/srv/hooks\"",
+                "",
+                "# debug=true",
+            ]
+        );
+    }
+
+    #[test]
+    fn sets_a_key_with_no_example_at_the_end() {
+        let mut config = config("token=\"xxx\"\n# debug=true");
+        config.set("priority", "5");
+
+        assert_eq!(
+            config.lines,
+            ["token=\"xxx\"", "# debug=true", "priority=\"5\""]
+        );
+    }
+
+    #[test]
+    fn updates_a_key_in_place_keeping_the_lines_around_it() {
+        let mut config = config(
+            "# The agent's name\n  name=\"old\"\n\n# Where builds go\nbuild-path=\"/builds\"",
+        );
+        config.set("name", "new");
+
+        assert_eq!(
+            config.lines,
+            [
+                "# The agent's name",
+                "  name=\"new\"",
+                "",
+                "# Where builds go",
+                "build-path=\"/builds\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn updates_the_last_of_a_key_set_twice() {
+        let mut config = config("name=\"first\"\nname=\"second\"");
+        config.set("name", "third");
+
+        assert_eq!(config.lines, ["name=\"first\"", "name=\"third\""]);
+        assert_eq!(config.get("name").as_deref(), Some("third"));
+    }
+
+    #[test]
+    fn unsets_every_line_but_leaves_examples() {
+        let mut config = config("# name=\"example\"\nname=\"a\"\ntags=\"x\"\nname=\"b\"");
+
+        assert!(config.unset("name"));
+        assert_eq!(config.lines, ["# name=\"example\"", "tags=\"x\""]);
+        assert!(!config.unset("name"));
+    }
+}
@@ -0,0 +1,73 @@
+/// Something worth knowing about that the agent mentioned in its log output.
+pub enum LogEvent {
+    Registered {
+        name: String,
+    },
+    JobAccepted {
+        job: String,
+    },
+    JobStarted {
+        job: String,
+        build_url: Option<String>,
+    },
+    JobFinished {
+        job: String,
+        build_url: Option<String>,
+        exit_status: Option<i32>,
+    },
+}
+
+/// Picks lifecycle messages out of a line of the agent's log, such as
+///
+/// ```text
+/// 2024-05-01 10:00:01 INFO   my-agent-1 Starting job 0190... for build at https://buildkite.com/acme/app/builds/42
+/// ```
+///
+/// Lines that don't match any of the messages bap knows about are ignored.
+pub fn parse_line(line: &str) -> Option<LogEvent> {
+    if let Some(rest) = after(line, "Successfully registered agent \"") {
+        let name = rest.split('"').next()?.to_string();
+        return Some(LogEvent::Registered { name });
+    }
+
+    if let Some(rest) = after(line, "Assigned job ") {
+        return Some(LogEvent::JobAccepted {
+            job: first_word(rest)?,
+        });
+    }
+
+    if let Some(rest) = after(line, "Starting job ") {
+        return Some(LogEvent::JobStarted {
+            job: first_word(rest)?,
+            build_url: build_url(rest),
+        });
+    }
+
+    if let Some(rest) = after(line, "Finished job ") {
+        return Some(LogEvent::JobFinished {
+            job: first_word(rest)?,
+            build_url: build_url(rest),
+            exit_status: after(rest, "exit status ")
+                .and_then(first_word)
+                .and_then(|status| status.parse().ok()),
+        });
+    }
+
+    None
+}
+
+fn after<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    line.find(marker).map(|i| &line[i + marker.len()..])
+}
+
+fn first_word(text: &str) -> Option<String> {
+    let word = text
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ')')
+        .next()?
+        .trim_end_matches('.');
+    (!word.is_empty()).then(|| word.to_string())
+}
+
+fn build_url(text: &str) -> Option<String> {
+    after(text, "for build at ").and_then(first_word)
+}
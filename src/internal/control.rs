@@ -0,0 +1,110 @@
+use crate::utils::run_dir;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long to wait for the daemon to answer a request.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request to the bap daemon. Requests are sent as one JSON object per line,
+/// tagged with a `command` field, e.g. `{"command":"stop","id":"1"}`.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// List the agents the daemon is running.
+    List,
+    /// Start an agent. `args` are any extra `bap run` arguments.
+    Start {
+        version: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// Stop an agent once its current job finishes, or straight away with `cancel`.
+    Stop {
+        id: String,
+        #[serde(default)]
+        cancel: bool,
+    },
+    /// Stop an agent once its current job finishes, then start it again with
+    /// the same version and arguments. It comes back with a new id.
+    Restart { id: String },
+    /// Fetch the most recent lines of an agent's output.
+    Logs {
+        id: String,
+        #[serde(default = "default_log_lines")]
+        lines: usize,
+    },
+    /// Keep the connection open and receive every lifecycle event as it happens.
+    Subscribe,
+}
+
+fn default_log_lines() -> usize {
+    100
+}
+
+/// What the daemon knows about one of its agents.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AgentStatus {
+    pub id: String,
+    pub version: String,
+    /// One of `starting`, `running`, `stopping`, `restarting` or `exited`.
+    pub state: String,
+    pub pid: Option<u32>,
+    /// Unix timestamp, in seconds, of when the agent was first started.
+    pub started_at: i64,
+    pub restarts: usize,
+    pub last_exit_code: Option<i32>,
+}
+
+pub fn default_socket_path() -> PathBuf {
+    run_dir().join("bap.sock")
+}
+
+/// A connection to a running bap daemon, for sending requests one at a time.
+/// Subscriptions aren't supported, they need a connection to themselves.
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    pub fn connect(socket: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket)
+            .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    /// Sends a request and waits for its reply. A reply with `"ok": false` is
+    /// turned into an error carrying the daemon's message.
+    pub fn request(&mut self, request: &Request) -> Result<Value> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        self.writer
+            .write_all(&line)
+            .context("Failed to send request to the bap daemon")?;
+
+        let mut reply = String::new();
+        if self.reader.read_line(&mut reply)? == 0 {
+            anyhow::bail!("The bap daemon closed the connection");
+        }
+        let reply: Value =
+            serde_json::from_str(&reply).context("Invalid reply from the bap daemon")?;
+        if reply["ok"] != Value::Bool(true) {
+            anyhow::bail!(
+                "{}",
+                reply["error"]
+                    .as_str()
+                    .unwrap_or("The bap daemon refused the request")
+            );
+        }
+        Ok(reply)
+    }
+}
@@ -0,0 +1,206 @@
+use crate::internal::agent_log::{self, LogEvent};
+use crate::internal::output::LineObserver;
+use anyhow::{Context, Result};
+use chrono::Local;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::Path;
+use tokio::sync::broadcast;
+
+/// Where `bap run --events` writes when no file or descriptor is given.
+const DEFAULT_EVENTS_FD: RawFd = 3;
+
+/// Something that happened to an agent bap is supervising.
+#[derive(Serialize, Clone)]
+pub struct Event {
+    /// When it happened, as an RFC 3339 timestamp.
+    pub time: String,
+    /// The id of the agent it happened to, missing for events from before
+    /// any agent was started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn new(agent: &str, version: &str, kind: EventKind) -> Self {
+        Self {
+            time: Local::now().to_rfc3339(),
+            agent: Some(agent.to_string()),
+            version: Some(version.to_string()),
+            kind,
+        }
+    }
+
+    /// An event that isn't about a particular agent yet, such as choosing
+    /// which version to run.
+    pub fn general(version: Option<&str>, kind: EventKind) -> Self {
+        Self {
+            time: Local::now().to_rfc3339(),
+            agent: None,
+            version: version.map(str::to_string),
+            kind,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum EventFormat {
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// bap is working out which version to run, `requested` if one was given.
+    Resolving { requested: Option<String> },
+    /// The version wasn't installed, so bap is installing it.
+    Installing,
+    /// The agent process was started.
+    Spawned { pid: u32 },
+    /// bap received a signal and passed it on to the agent.
+    SignalReceived { signal: String },
+    /// The agent process exited. `requested` says whether bap asked it to.
+    Exited {
+        code: i32,
+        description: String,
+        requested: bool,
+    },
+    /// The agent will be started again after `delay_secs`.
+    Restarting { delay_secs: u64, restarts: usize },
+    /// The agent restarted too often and won't be started again.
+    GaveUp { restarts: usize },
+    /// The agent's run window closed or it reached its maximum run duration,
+    /// so bap asked it to stop once its current job finishes.
+    Pausing { reason: String },
+    /// bap is waiting for the run window to open, at `until`, to start the agent.
+    Waiting { until: String },
+    /// bap has finished supervising the agent.
+    Stopped { code: i32 },
+    /// The agent registered with Buildkite.
+    Registered { name: String },
+    /// The agent was assigned a job and is accepting it.
+    JobAccepted { job: String },
+    JobStarted {
+        job: String,
+        build_url: Option<String>,
+    },
+    JobFinished {
+        job: String,
+        build_url: Option<String>,
+        exit_status: Option<i32>,
+    },
+}
+
+/// Turns what the agent says in its output into events.
+pub struct LogWatcher {
+    agent: String,
+    version: String,
+    events: broadcast::Sender<Event>,
+}
+
+impl LogWatcher {
+    pub fn new(agent: &str, version: &str, events: broadcast::Sender<Event>) -> Self {
+        Self {
+            agent: agent.to_string(),
+            version: version.to_string(),
+            events,
+        }
+    }
+}
+
+impl LineObserver for LogWatcher {
+    fn line(&self, line: &[u8]) {
+        let Some(event) = agent_log::parse_line(&String::from_utf8_lossy(line)) else {
+            return;
+        };
+
+        let kind = match event {
+            LogEvent::Registered { name } => EventKind::Registered { name },
+            LogEvent::JobAccepted { job } => EventKind::JobAccepted { job },
+            LogEvent::JobStarted { job, build_url } => EventKind::JobStarted { job, build_url },
+            LogEvent::JobFinished {
+                job,
+                build_url,
+                exit_status,
+            } => EventKind::JobFinished {
+                job,
+                build_url,
+                exit_status,
+            },
+        };
+        let _ = self
+            .events
+            .send(Event::new(&self.agent, &self.version, kind));
+    }
+}
+
+/// Opens where `bap run --events` should write to: a file, or a file
+/// descriptor the caller has opened for us.
+pub fn open_sink(path: Option<&Path>, fd: Option<RawFd>) -> Result<File> {
+    if let Some(path) = path {
+        return OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open events file: {}", path.display()));
+    }
+
+    let fd = fd.unwrap_or(DEFAULT_EVENTS_FD);
+    // The runtime opens descriptors of its own (epoll, eventfd) which can land
+    // on a number the caller left free, so only take ones that lead somewhere.
+    // SAFETY: fstat only fills in the zeroed struct we hand it.
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    let usable = unsafe { libc::fstat(fd, &mut stat) } == 0
+        && matches!(
+            stat.st_mode & libc::S_IFMT,
+            libc::S_IFREG | libc::S_IFIFO | libc::S_IFSOCK | libc::S_IFCHR
+        );
+    if !usable {
+        anyhow::bail!(
+            "File descriptor {} isn't open, open it (e.g. `3>events.jsonl`) or use --events-file",
+            fd
+        );
+    }
+    // Work on a copy so the original is left alone for whoever opened it.
+    // SAFETY: dup returns a fresh descriptor that nothing else owns.
+    let copy = unsafe { libc::dup(fd) };
+    if copy == -1 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to use file descriptor {}", fd));
+    }
+    Ok(unsafe { File::from_raw_fd(copy) })
+}
+
+/// Writes each event to `sink` as a line of JSON until every sender is gone.
+pub async fn write_json_lines(mut sink: File, mut events: broadcast::Receiver<Event>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                eprintln!(
+                    "Dropped {} events, the events file couldn't keep up",
+                    missed
+                );
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        let Ok(mut line) = serde_json::to_vec(&event) else {
+            continue;
+        };
+        line.push(b'\n');
+        if let Err(e) = sink.write_all(&line).and_then(|_| sink.flush()) {
+            eprintln!("Failed to write event: {}", e);
+            return;
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::internal::agent_log::{self, LogEvent};
+use crate::internal::output::LineObserver;
+use crate::utils::bap_root;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// A job that an agent run by bap finished, as kept in the history file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JobRecord {
+    pub job: String,
+    /// `<organization>/<pipeline>`, taken from the build URL.
+    pub pipeline: Option<String>,
+    pub build_url: Option<String>,
+    pub version: String,
+    /// The name the agent registered with, if bap saw it.
+    pub agent: Option<String>,
+    /// RFC 3339 timestamps. The start is missing if the agent was already
+    /// running the job when bap started watching it.
+    pub started_at: Option<String>,
+    pub finished_at: String,
+    pub exit_status: Option<i32>,
+}
+
+impl JobRecord {
+    pub fn finished_at(&self) -> Option<DateTime<Local>> {
+        parse_time(&self.finished_at)
+    }
+
+    pub fn started_at(&self) -> Option<DateTime<Local>> {
+        self.started_at.as_deref().and_then(parse_time)
+    }
+}
+
+pub fn history_file() -> PathBuf {
+    bap_root().join("history.jsonl")
+}
+
+/// Appends a finished job to the history file. Each record is a single write
+/// to a file opened for appending, so agents finishing jobs at the same time
+/// don't interleave.
+pub fn append(record: &JobRecord) -> Result<()> {
+    let path = history_file();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&line))
+        .with_context(|| format!("Failed to write job history: {}", path.display()))
+}
+
+/// Reads every job in the history file, oldest first. Lines that can't be
+/// read, such as one cut short by a full disk, are skipped.
+pub fn load() -> Result<Vec<JobRecord>> {
+    let path = history_file();
+    let file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to read job history: {}", path.display()))
+        }
+    };
+
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect())
+}
+
+/// Works out the pipeline from a build URL such as
+/// `https://buildkite.com/acme/app/builds/42`, giving `acme/app`.
+pub fn pipeline_from_url(url: &str) -> Option<String> {
+    let path = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let mut segments = path.split('/').skip(1);
+    let organization = segments.next().filter(|s| !s.is_empty())?;
+    let pipeline = segments.next().filter(|s| !s.is_empty())?;
+    Some(format!("{}/{}", organization, pipeline))
+}
+
+/// Watches an agent's output for jobs starting and finishing, and records each
+/// finished job in the history file.
+pub struct HistoryRecorder {
+    version: String,
+    state: Mutex<RecorderState>,
+}
+
+#[derive(Default)]
+struct RecorderState {
+    agent: Option<String>,
+    /// When each running job started, and its build URL.
+    started: HashMap<String, (String, Option<String>)>,
+}
+
+impl HistoryRecorder {
+    pub fn new(version: &str) -> Self {
+        Self {
+            version: version.to_string(),
+            state: Mutex::default(),
+        }
+    }
+}
+
+impl LineObserver for HistoryRecorder {
+    fn line(&self, line: &[u8]) {
+        let Some(event) = agent_log::parse_line(&String::from_utf8_lossy(line)) else {
+            return;
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Local::now().to_rfc3339();
+        match event {
+            LogEvent::Registered { name } => state.agent = Some(name),
+            LogEvent::JobStarted { job, build_url } => {
+                state.started.insert(job, (now, build_url));
+            }
+            LogEvent::JobFinished {
+                job,
+                build_url,
+                exit_status,
+            } => {
+                let (started_at, start_url) = state.started.remove(&job).unzip();
+                let build_url = build_url.or(start_url.flatten());
+                let record = JobRecord {
+                    job,
+                    pipeline: build_url.as_deref().and_then(pipeline_from_url),
+                    build_url,
+                    version: self.version.clone(),
+                    agent: state.agent.clone(),
+                    started_at,
+                    finished_at: now,
+                    exit_status,
+                };
+                if let Err(e) = append(&record) {
+                    eprintln!("{:#}", e);
+                }
+            }
+            LogEvent::JobAccepted { .. } => {}
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Local>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Local))
+}
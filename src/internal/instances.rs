@@ -0,0 +1,112 @@
+use crate::utils::run_dir;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A background agent started with `bap start`. Each one is recorded in a
+/// JSON pidfile under `~/.bap/run/`, next to the log file it writes to.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Instance {
+    pub id: String,
+    pub version: String,
+    /// The pid of the detached `bap run` process supervising the agent.
+    pub pid: u32,
+    /// Unix timestamp, in seconds, of when the agent was started.
+    pub started_at: i64,
+    pub cwd: PathBuf,
+    pub log: PathBuf,
+    /// The arguments `bap run` was started with, so the agent can be restarted.
+    pub args: Vec<String>,
+    /// The profile the agent runs with, from `--profile` or the project's
+    /// `.bap.toml`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+impl Instance {
+    pub fn save(&self) -> Result<()> {
+        let path = state_path(&self.id);
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to write pidfile: {}", path.display()))
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        let path = state_path(&self.id);
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove pidfile: {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        is_process_alive(self.pid)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        let secs = Utc::now().timestamp() - self.started_at;
+        Duration::from_secs(secs.max(0) as u64)
+    }
+}
+
+/// Lists the background agents that are still running, clearing out the
+/// pidfiles of any that have exited since they were started.
+pub fn list() -> Result<Vec<Instance>> {
+    let dir = run_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut instances = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read pidfile: {}", path.display()))?;
+        let Ok(instance) = serde_json::from_str::<Instance>(&content) else {
+            eprintln!("Ignoring unreadable pidfile: {}", path.display());
+            continue;
+        };
+
+        if instance.is_running() {
+            instances.push(instance);
+        } else {
+            instance.remove()?;
+        }
+    }
+
+    instances.sort_by_key(|instance| instance.id.parse::<u64>().unwrap_or(u64::MAX));
+    Ok(instances)
+}
+
+/// The lowest id not used by a running agent.
+pub fn next_id() -> Result<String> {
+    let taken: Vec<String> = list()?.into_iter().map(|i| i.id).collect();
+    let id = (1..)
+        .map(|n: u64| n.to_string())
+        .find(|id| !taken.contains(id))
+        .expect("ran out of instance ids");
+    Ok(id)
+}
+
+pub fn log_path(id: &str) -> PathBuf {
+    run_dir().join(format!("{}.log", id))
+}
+
+fn state_path(id: &str) -> PathBuf {
+    run_dir().join(format!("{}.json", id))
+}
+
+pub fn is_process_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 performs the permission and existence checks only,
+    // nothing is delivered to the process.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
@@ -0,0 +1,171 @@
+use crate::config::LogSettings;
+use crate::internal::output::LineObserver;
+use crate::utils::logs_dir;
+use anyhow::{Context, Result};
+use chrono::Local;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// A log file that rotates itself by size and age. Rotated files are renamed
+/// `<name>.1`, `<name>.2` and so on, with `.1` always the most recent.
+pub struct RotatingLog {
+    path: PathBuf,
+    settings: LogSettings,
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+}
+
+impl RotatingLog {
+    pub fn open(path: &Path, settings: LogSettings) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create log directory: {}", parent.display()))?;
+        }
+
+        let (file, size, opened_at) = open_current(path)?;
+        let mut log = Self {
+            path: path.to_path_buf(),
+            settings,
+            file,
+            size,
+            opened_at,
+        };
+
+        if log.needs_rotation() {
+            log.rotate()?;
+        }
+
+        Ok(log)
+    }
+
+    /// Appends a line to the log, prefixed with the current local time.
+    pub fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        if self.needs_rotation() {
+            self.rotate()?;
+        }
+
+        let timestamp = Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z ").to_string();
+        self.file.write_all(timestamp.as_bytes())?;
+        self.file.write_all(line)?;
+        self.file.write_all(b"\n")?;
+        self.size += (timestamp.len() + line.len() + 1) as u64;
+        Ok(())
+    }
+
+    fn needs_rotation(&self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+
+        let too_big = self.size >= self.settings.max_size_mb * 1024 * 1024;
+        let max_age = Duration::from_secs(self.settings.max_age_hours * 60 * 60);
+        let too_old = self
+            .opened_at
+            .elapsed()
+            .map(|age| age >= max_age)
+            .unwrap_or(false);
+
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.settings.retain == 0 {
+            fs::remove_file(&self.path)
+                .with_context(|| format!("Failed to remove log file: {}", self.path.display()))?;
+        } else {
+            // The oldest file falls off the end, it's fine if it doesn't exist yet
+            let _ = fs::remove_file(rotated_path(&self.path, self.settings.retain));
+
+            for index in (1..self.settings.retain).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, index + 1)).with_context(|| {
+                        format!("Failed to rotate log file: {}", from.display())
+                    })?;
+                }
+            }
+
+            fs::rename(&self.path, rotated_path(&self.path, 1))
+                .with_context(|| format!("Failed to rotate log file: {}", self.path.display()))?;
+        }
+
+        let (file, size, opened_at) = open_current(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened_at = opened_at;
+        Ok(())
+    }
+}
+
+/// Feeds every line of agent output into a [`RotatingLog`].
+pub struct LogRecorder(Mutex<RotatingLog>);
+
+impl LogRecorder {
+    pub fn new(log: RotatingLog) -> Self {
+        Self(Mutex::new(log))
+    }
+}
+
+impl LineObserver for LogRecorder {
+    fn line(&self, line: &[u8]) {
+        let mut log = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = log.write_line(line) {
+            eprintln!(
+                "Failed to write to log file {}: {:#}",
+                log.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Where `bap run --log-file` writes when no path is given.
+pub fn default_log_path(version: &str) -> PathBuf {
+    logs_dir().join(version).join("agent.log")
+}
+
+/// Where the daemon's agent `id` writes with `--log-file` and no path, as
+/// several of the same version can be running at once.
+pub fn daemon_log_path(version: &str, id: &str) -> PathBuf {
+    default_log_path(version).with_file_name(format!("daemon-{}.log", id))
+}
+
+/// Where the `number`th of several agents started with `bap run --count`
+/// writes, the first one taking the default path.
+pub fn instance_log_path(version: &str, number: u32) -> PathBuf {
+    let path = default_log_path(version);
+    match number {
+        1 => path,
+        n => path.with_file_name(format!("agent-{}.log", n)),
+    }
+}
+
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+fn open_current(path: &Path) -> Result<(File, u64, SystemTime)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file: {}", path.display()))?;
+
+    let metadata = file.metadata()?;
+    let opened_at = if metadata.len() == 0 {
+        SystemTime::now()
+    } else {
+        metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now())
+    };
+
+    Ok((file, metadata.len(), opened_at))
+}
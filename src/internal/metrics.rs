@@ -0,0 +1,229 @@
+use crate::internal::events::{Event, EventKind};
+use crate::internal::process::{cpu_seconds, resident_bytes};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+#[derive(Default)]
+struct AgentMetrics {
+    version: String,
+    pid: Option<u32>,
+    started: Option<Instant>,
+    restarts: u64,
+    last_exit_code: Option<i32>,
+    exits: BTreeMap<i32, u64>,
+    jobs_started: u64,
+    jobs_finished: u64,
+}
+
+/// Per-agent counters and gauges, kept up to date from lifecycle events and
+/// served in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    agents: Mutex<BTreeMap<String, AgentMetrics>>,
+}
+
+impl Metrics {
+    pub fn record(&self, event: &Event) {
+        let mut agents = self.agents.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(id) = &event.agent else {
+            return;
+        };
+        let agent = agents.entry(id.clone()).or_default();
+        agent.version = event.version.clone().unwrap_or_default();
+
+        match &event.kind {
+            EventKind::Spawned { pid } => {
+                agent.pid = Some(*pid);
+                agent.started = Some(Instant::now());
+            }
+            EventKind::Exited { code, .. } => {
+                agent.pid = None;
+                agent.started = None;
+                agent.last_exit_code = Some(*code);
+                *agent.exits.entry(*code).or_default() += 1;
+            }
+            EventKind::Restarting { .. } => agent.restarts += 1,
+            EventKind::JobStarted { .. } => agent.jobs_started += 1,
+            EventKind::JobFinished { .. } => agent.jobs_finished += 1,
+            _ => {}
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let agents = self.agents.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+
+        let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        };
+
+        let each = |value: &dyn Fn(&AgentMetrics) -> Option<String>| {
+            agents
+                .iter()
+                .filter_map(|(id, agent)| Some((labels(id, agent), value(agent)?)))
+                .collect::<Vec<_>>()
+        };
+
+        family(
+            "bap_agent_up",
+            "gauge",
+            "Whether the agent process is running.",
+            each(&|a| Some(u8::from(a.pid.is_some()).to_string())),
+        );
+        family(
+            "bap_agent_uptime_seconds",
+            "gauge",
+            "How long the current agent process has been running.",
+            each(&|a| a.started.map(|s| s.elapsed().as_secs_f64().to_string())),
+        );
+        family(
+            "bap_agent_restarts_total",
+            "counter",
+            "How many times bap has restarted the agent.",
+            each(&|a| Some(a.restarts.to_string())),
+        );
+        family(
+            "bap_agent_last_exit_code",
+            "gauge",
+            "The exit code of the agent's most recent exit.",
+            each(&|a| a.last_exit_code.map(|c| c.to_string())),
+        );
+        family(
+            "bap_agent_exits_total",
+            "counter",
+            "How many times the agent has exited, by exit code.",
+            agents
+                .iter()
+                .flat_map(|(id, agent)| {
+                    agent.exits.iter().map(move |(code, count)| {
+                        (
+                            format!("{},code=\"{}\"", labels(id, agent), code),
+                            count.to_string(),
+                        )
+                    })
+                })
+                .collect(),
+        );
+        family(
+            "bap_agent_jobs_started_total",
+            "counter",
+            "How many jobs the agent has started.",
+            each(&|a| Some(a.jobs_started.to_string())),
+        );
+        family(
+            "bap_agent_jobs_finished_total",
+            "counter",
+            "How many jobs the agent has finished.",
+            each(&|a| Some(a.jobs_finished.to_string())),
+        );
+        family(
+            "bap_agent_cpu_seconds_total",
+            "counter",
+            "CPU time used by the agent process and the children it has waited for.",
+            each(&|a| a.pid.and_then(cpu_seconds).map(|s| s.to_string())),
+        );
+        family(
+            "bap_agent_resident_memory_bytes",
+            "gauge",
+            "Resident memory of the agent process.",
+            each(&|a| a.pid.and_then(resident_bytes).map(|b| b.to_string())),
+        );
+
+        out
+    }
+}
+
+fn labels(id: &str, agent: &AgentMetrics) -> String {
+    format!(
+        "agent=\"{}\",version=\"{}\"",
+        escape_label(id),
+        escape_label(&agent.version)
+    )
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Starts serving metrics for the agents sending events down `events` at
+/// `addr`/metrics. This is deliberately a very small HTTP server, a scraper is
+/// the only client it needs to handle.
+pub async fn start(addr: SocketAddr, events: &broadcast::Sender<Event>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen for metrics on {}", addr))?;
+    println!("Serving metrics on http://{}/metrics", addr);
+
+    let metrics = Arc::new(Metrics::default());
+    tokio::spawn(track(metrics.clone(), events.subscribe()));
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                let _ = respond(stream, &metrics).await;
+            });
+        }
+    });
+
+    Ok(())
+}
+
+async fn track(metrics: Arc<Metrics>, mut events: broadcast::Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => metrics.record(&event),
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, content_type, body) = if path == "/metrics" {
+        (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            metrics.render(),
+        )
+    } else {
+        ("404 Not Found", "text/plain", "Not found\n".to_string())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -1 +1,19 @@
+pub mod agent_config;
+pub mod agent_log;
 pub mod api;
+pub mod control;
+pub mod events;
+pub mod history;
+pub mod instances;
+pub mod log_file;
+pub mod metrics;
+pub mod notify;
+pub mod output;
+pub mod preflight;
+pub mod process;
+pub mod profile;
+pub mod project;
+pub mod restart;
+pub mod schedule;
+pub mod signals;
+pub mod token;
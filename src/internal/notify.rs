@@ -0,0 +1,250 @@
+use crate::config::{NotificationHook, NotifyOn};
+use crate::internal::events::{Event, EventKind};
+use anyhow::{Context, Result};
+use futures_util::future::join_all;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// How long a webhook or command gets before it counts as failed.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before the first retry. Each one after waits twice as long.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The period `max_per_hour` applies to.
+const RATE_LIMIT_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Starts sending notifications for the events coming down `events`. The task
+/// returned finishes once every sender is gone and the last notifications
+/// have been delivered, or there are no hooks to notify.
+pub fn start(
+    hooks: Vec<NotificationHook>,
+    events: &broadcast::Sender<Event>,
+) -> Result<Option<JoinHandle<()>>> {
+    for hook in &hooks {
+        if hook.url.is_some() == hook.command.is_some() {
+            anyhow::bail!("Each notification in the config needs either a url or a command");
+        }
+    }
+
+    if hooks.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(tokio::spawn(notify(hooks, events.subscribe()))))
+}
+
+async fn notify(hooks: Vec<NotificationHook>, mut events: broadcast::Receiver<Event>) {
+    let client = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    let hostname = hostname();
+    let mut limits: Vec<RateLimit> = hooks
+        .iter()
+        .map(|hook| RateLimit::new(hook.max_per_hour))
+        .collect();
+    let mut deliveries: Vec<JoinHandle<()>> = Vec::new();
+    // Whether each agent's last exit was clean, which decides if its stopping
+    // is worth a notification or has been covered by a crash already
+    let mut clean_exits: HashMap<String, bool> = HashMap::new();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if let (
+            Some(agent),
+            EventKind::Exited {
+                code, requested, ..
+            },
+        ) = (&event.agent, &event.kind)
+        {
+            clean_exits.insert(agent.clone(), *code == 0 || *requested);
+        }
+        let clean_exit = event
+            .agent
+            .as_ref()
+            .and_then(|agent| clean_exits.get(agent))
+            .copied()
+            .unwrap_or(true);
+        let Some((trigger, text)) = describe(&event, &hostname, clean_exit) else {
+            continue;
+        };
+
+        for (hook, limit) in hooks.iter().zip(&mut limits) {
+            if !hook.on.contains(&trigger) {
+                continue;
+            }
+            let Some(suppressed) = limit.take() else {
+                continue;
+            };
+
+            let payload = json!({
+                "notification": trigger,
+                "text": text,
+                "hostname": hostname,
+                "suppressed": suppressed,
+                "event": event,
+            });
+            deliveries.push(tokio::spawn(deliver(client.clone(), hook.clone(), payload)));
+        }
+        deliveries.retain(|delivery| !delivery.is_finished());
+    }
+
+    join_all(deliveries).await;
+}
+
+/// Whether an event is worth a notification, and if so a line saying what
+/// happened that's fit for a chat message. `clean_exit` says whether the agent
+/// last exited successfully or because it was asked to, as only then is its
+/// stopping news.
+fn describe(event: &Event, hostname: &str, clean_exit: bool) -> Option<(NotifyOn, String)> {
+    let agent = format!(
+        "Buildkite agent {} on {}",
+        event.agent.as_deref()?,
+        hostname
+    );
+
+    match &event.kind {
+        EventKind::Exited {
+            code,
+            description,
+            requested: false,
+        } if *code != 0 => Some((NotifyOn::Crashed, format!("{} {}", agent, description))),
+        EventKind::GaveUp { restarts } => Some((
+            NotifyOn::GaveUp,
+            format!("{} gave up after {} restarts", agent, restarts),
+        )),
+        EventKind::Stopped { code } if clean_exit => Some((
+            NotifyOn::Stopped,
+            format!("{} stopped with exit code {}", agent, code),
+        )),
+        _ => None,
+    }
+}
+
+/// Sends a notification, retrying with a growing delay if it fails.
+async fn deliver(client: reqwest::Client, hook: NotificationHook, payload: Value) {
+    let mut delay = FIRST_RETRY_DELAY;
+    for attempt in 0..=hook.retries {
+        let result = match (&hook.url, &hook.command) {
+            (Some(url), _) => post(&client, url, &payload).await,
+            (_, Some(command)) => run_command(command, &payload).await,
+            (None, None) => return,
+        };
+
+        match result {
+            Ok(()) => return,
+            Err(e) if attempt == hook.retries => {
+                let target = hook.url.as_ref().or(hook.command.as_ref());
+                eprintln!(
+                    "Failed to send notification to {}: {:#}",
+                    target.map_or("", String::as_str),
+                    e
+                );
+            }
+            Err(_) => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &str, payload: &Value) -> Result<()> {
+    client
+        .post(url)
+        .json(payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Runs a notification command through the shell, with the notification as
+/// JSON on its standard input and the basics in `BAP_*` variables.
+async fn run_command(command: &str, payload: &Value) -> Result<()> {
+    let event = &payload["event"];
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env(
+            "BAP_NOTIFICATION",
+            payload["notification"].as_str().unwrap_or(""),
+        )
+        .env("BAP_MESSAGE", payload["text"].as_str().unwrap_or(""))
+        .env("BAP_AGENT", event["agent"].as_str().unwrap_or(""))
+        .env("BAP_VERSION", event["version"].as_str().unwrap_or(""))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to run notification command")?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that doesn't read its input is fine
+        let _ = stdin.write_all(payload.to_string().as_bytes()).await;
+    }
+
+    let status = tokio::time::timeout(DELIVERY_TIMEOUT, child.wait())
+        .await
+        .context("Notification command timed out")??;
+    if !status.success() {
+        anyhow::bail!("Notification command {}", status);
+    }
+    Ok(())
+}
+
+/// Keeps a hook to `max_per_hour` notifications, counting the ones it drops.
+struct RateLimit {
+    max_per_hour: usize,
+    sent: VecDeque<Instant>,
+    dropped: usize,
+}
+
+impl RateLimit {
+    fn new(max_per_hour: usize) -> Self {
+        Self {
+            max_per_hour,
+            sent: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Takes a slot for a notification if there's one free, returning how many
+    /// were dropped since the last one went out.
+    fn take(&mut self) -> Option<usize> {
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| sent.elapsed() >= RATE_LIMIT_PERIOD)
+        {
+            self.sent.pop_front();
+        }
+
+        if self.sent.len() >= self.max_per_hour {
+            self.dropped += 1;
+            return None;
+        }
+        self.sent.push_back(Instant::now());
+        Some(std::mem::take(&mut self.dropped))
+    }
+}
+
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: gethostname writes at most buffer.len() bytes into the buffer.
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return "unknown host".to_string();
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
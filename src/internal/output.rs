@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{ChildStderr, ChildStdout};
+use tokio::task::JoinHandle;
+
+const BUFFER_SIZE: usize = 8192;
+
+/// How long to keep draining output after the agent has exited. Job processes
+/// the agent left behind can hold the pipes open indefinitely, so this puts a
+/// bound on how long `bap run` waits for them.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Something that wants to see the agent's output a line at a time, such as a
+/// log file. Lines are passed without their trailing newline.
+pub trait LineObserver: Send + Sync {
+    fn line(&self, line: &[u8]);
+}
+
+pub type Observers = Vec<Arc<dyn LineObserver>>;
+
+/// Where the agent's own output ends up, besides any observers.
+#[derive(Clone)]
+pub enum OutputMode {
+    /// Passed through to bap's stdout and stderr untouched.
+    Raw,
+    /// Passed through a line at a time, each line starting with the prefix, so
+    /// several agents can share one terminal.
+    Prefixed(String),
+    /// Only seen by the observers.
+    Discard,
+}
+
+/// Keeps the last few lines of output in memory.
+pub struct RecentLines {
+    capacity: usize,
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl RecentLines {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// The most recent `count` lines, oldest first.
+    pub fn last(&self, count: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+}
+
+impl LineObserver for RecentLines {
+    fn line(&self, line: &[u8]) {
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(String::from_utf8_lossy(line).into_owned());
+    }
+}
+
+/// Passes the agent's stdout and stderr through to bap's own, byte for byte.
+///
+/// Each stream gets its own task so neither can hold up the other, and every
+/// chunk is written out and flushed as soon as it's read, which keeps the
+/// interleaving of the two streams as close as possible to what the agent
+/// produced. Observers are handed complete lines from both streams on the side.
+pub struct OutputForwarder {
+    stdout: JoinHandle<Result<()>>,
+    stderr: JoinHandle<Result<()>>,
+}
+
+impl OutputForwarder {
+    pub fn spawn(
+        stdout: ChildStdout,
+        stderr: ChildStderr,
+        observers: Observers,
+        mode: OutputMode,
+    ) -> Self {
+        Self {
+            stdout: tokio::spawn(pump(
+                stdout,
+                tokio::io::stdout(),
+                observers.clone(),
+                mode.clone(),
+            )),
+            stderr: tokio::spawn(pump(stderr, tokio::io::stderr(), observers, mode)),
+        }
+    }
+
+    /// Waits for both streams to reach EOF so nothing the agent wrote is lost.
+    /// Streams still open after `DRAIN_TIMEOUT`, held by something the agent
+    /// left running, are abandoned so they can't mix with a restart's output.
+    pub async fn finish(mut self) -> Result<()> {
+        let drained = async {
+            let stdout = (&mut self.stdout)
+                .await
+                .context("Failed to forward stdout")?;
+            let stderr = (&mut self.stderr)
+                .await
+                .context("Failed to forward stderr")?;
+            stdout.context("Failed to forward stdout")?;
+            stderr.context("Failed to forward stderr")
+        };
+
+        match tokio::time::timeout(DRAIN_TIMEOUT, drained).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.stdout.abort();
+                self.stderr.abort();
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn pump<R, W>(
+    mut reader: R,
+    mut writer: W,
+    observers: Observers,
+    mode: OutputMode,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut lines = LineSplitter::default();
+    let mut prefixed = Vec::new();
+    let emit = |line: &[u8], prefixed: &mut Vec<u8>| {
+        observers.iter().for_each(|observer| observer.line(line));
+        if let OutputMode::Prefixed(prefix) = &mode {
+            prefixed.extend_from_slice(prefix.as_bytes());
+            prefixed.push(b' ');
+            prefixed.extend_from_slice(line);
+            prefixed.push(b'\n');
+        }
+    };
+
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        let chunk = &buffer[..read];
+
+        if !matches!(mode, OutputMode::Raw) || !observers.is_empty() {
+            lines.push(chunk, |line| emit(line, &mut prefixed));
+        }
+
+        match mode {
+            OutputMode::Raw => writer.write_all(chunk).await?,
+            OutputMode::Prefixed(_) => {
+                writer.write_all(&prefixed).await?;
+                prefixed.clear();
+            }
+            OutputMode::Discard => continue,
+        }
+        writer.flush().await?;
+    }
+
+    lines.finish(|line| emit(line, &mut prefixed));
+    writer.write_all(&prefixed).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reassembles lines from chunks that may split them at arbitrary points.
+#[derive(Default)]
+struct LineSplitter {
+    partial: Vec<u8>,
+}
+
+impl LineSplitter {
+    fn push(&mut self, chunk: &[u8], mut emit: impl FnMut(&[u8])) {
+        let mut rest = chunk;
+        while let Some(newline) = rest.iter().position(|&b| b == b'\n') {
+            self.partial.extend_from_slice(&rest[..newline]);
+            emit(strip_carriage_return(&self.partial));
+            self.partial.clear();
+            rest = &rest[newline + 1..];
+        }
+        self.partial.extend_from_slice(rest);
+    }
+
+    fn finish(&mut self, mut emit: impl FnMut(&[u8])) {
+        if !self.partial.is_empty() {
+            emit(strip_carriage_return(&self.partial));
+            self.partial.clear();
+        }
+    }
+}
+
+fn strip_carriage_return(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
@@ -0,0 +1,234 @@
+use crate::internal::agent_config::AgentConfig;
+use crate::internal::token::PLACEHOLDER_TOKEN;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Settings `buildkite-agent start` reads from its config file.
+const KNOWN_KEYS: &[&str] = &[
+    "acquire-job",
+    "additional-hooks-paths",
+    "allowed-environment-variables",
+    "allowed-plugins",
+    "allowed-repositories",
+    "bootstrap-script",
+    "build-path",
+    "cancel-grace-period",
+    "cancel-signal",
+    "config",
+    "debug",
+    "debug-http",
+    "debug-signing",
+    "disconnect-after-idle-timeout",
+    "disconnect-after-job",
+    "disconnect-after-uptime",
+    "enable-environment-variable-allowlist",
+    "enable-job-log-tmpfile",
+    "endpoint",
+    "experiment",
+    "git-checkout-flags",
+    "git-clean-flags",
+    "git-clone-flags",
+    "git-clone-mirror-flags",
+    "git-fetch-flags",
+    "git-mirrors-lock-timeout",
+    "git-mirrors-path",
+    "git-mirrors-skip-update",
+    "git-submodule-clone-config",
+    "health-check-addr",
+    "hooks-path",
+    "job-log-path",
+    "job-verification-invalid-signature-behavior",
+    "job-verification-no-signature-behavior",
+    "kubernetes-exec",
+    "log-format",
+    "log-level",
+    "metrics-datadog",
+    "metrics-datadog-distributions",
+    "metrics-datadog-host",
+    "name",
+    "no-color",
+    "no-command-eval",
+    "no-feature-reporting",
+    "no-git-submodules",
+    "no-http2",
+    "no-local-hooks",
+    "no-plugin-validation",
+    "no-plugins",
+    "no-pty",
+    "no-ssh-keyscan",
+    "plugins-path",
+    "priority",
+    "profile",
+    "queue",
+    "redacted-vars",
+    "reflect-exit-status",
+    "shell",
+    "signal-grace-period-seconds",
+    "signing-aws-kms-key",
+    "signing-gcp-kms-key",
+    "signing-jwks-file",
+    "signing-jwks-key-id",
+    "skip-checkout",
+    "sockets-path",
+    "spawn",
+    "spawn-per-cpu",
+    "spawn-with-priority",
+    "strict-single-hooks",
+    "tags",
+    "tags-from-ec2-meta-data",
+    "tags-from-ec2-meta-data-paths",
+    "tags-from-ec2-tags",
+    "tags-from-ecs-meta-data",
+    "tags-from-gcp-labels",
+    "tags-from-gcp-meta-data",
+    "tags-from-gcp-meta-data-paths",
+    "tags-from-host",
+    "timestamp-lines",
+    "token",
+    "trace-context-encoding",
+    "tracing-backend",
+    "tracing-service-name",
+    "verification-failure-behavior",
+    "verification-jwks-file",
+    "wait-for-ec2-meta-data-timeout",
+    "wait-for-ec2-tags-timeout",
+    "wait-for-ecs-meta-data-timeout",
+    "wait-for-gcp-labels-timeout",
+    "write-job-logs-to-stdout",
+];
+
+/// Settings that have been renamed, and what they're called now.
+const DEPRECATED_KEYS: &[(&str, &str)] = &[
+    ("meta-data", "tags"),
+    ("meta-data-ec2", "tags-from-ec2-meta-data"),
+    ("meta-data-ec2-tags", "tags-from-ec2-tags"),
+    ("meta-data-gcp", "tags-from-gcp-meta-data"),
+    ("tags-from-ec2", "tags-from-ec2-meta-data"),
+    ("tags-from-gcp", "tags-from-gcp-meta-data"),
+    (
+        "no-automatic-ssh-fingerprint-verification",
+        "no-ssh-keyscan",
+    ),
+    (
+        "disconnect-after-job-timeout",
+        "disconnect-after-idle-timeout",
+    ),
+];
+
+/// Directories the config points the agent at, which ought to exist.
+const PATH_KEYS: &[&str] = &["build-path", "hooks-path", "plugins-path"];
+
+/// What's wrong with an agent's config file.
+pub struct Report {
+    /// Why the agent has no token to register with, which stops it starting.
+    pub missing_token: Option<String>,
+    /// Things the agent will start despite, but probably shouldn't.
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.missing_token.is_none() && self.warnings.is_empty()
+    }
+}
+
+/// Looks over a config file for problems. `token_elsewhere` says whether the
+/// agent gets a token some other way, in which case the file needn't have one.
+pub fn check(config: &AgentConfig, token_elsewhere: bool) -> Result<Report> {
+    let mut warnings = Vec::new();
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (key, _) in config.settings() {
+        *counts.entry(key).or_default() += 1;
+    }
+    let mut keys: Vec<(&str, usize)> = counts.into_iter().collect();
+    keys.sort();
+    for (key, count) in keys {
+        if let Some((_, replacement)) = DEPRECATED_KEYS.iter().find(|(old, _)| *old == key) {
+            warnings.push(format!(
+                "{} is deprecated, use {} instead",
+                key, replacement
+            ));
+        } else if !KNOWN_KEYS.contains(&key) {
+            warnings.push(format!("{} isn't a setting the agent knows about", key));
+        }
+        if count > 1 {
+            warnings.push(format!(
+                "{} is set {} times, only the last one counts",
+                key, count
+            ));
+        }
+    }
+
+    let base = config.path().parent().unwrap_or(Path::new("."));
+    for key in PATH_KEYS {
+        let Some(value) = config.get(key).filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        let path = expand_path(&value, base);
+        if !path.is_dir() {
+            warnings.push(format!("{} {} doesn't exist", key, path.display()));
+        }
+    }
+
+    let token = config.get("token").filter(|t| !t.trim().is_empty());
+    let missing_token = match token.as_deref() {
+        _ if token_elsewhere => None,
+        None => Some("there's no agent token".to_string()),
+        Some(PLACEHOLDER_TOKEN) => Some(format!(
+            "the agent token is still the placeholder {:?}",
+            PLACEHOLDER_TOKEN
+        )),
+        Some(_) => None,
+    };
+
+    if token.is_some_and(|token| token != PLACEHOLDER_TOKEN) {
+        let mode = std::fs::metadata(config.path())?.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            warnings.push(format!(
+                "the file holds the agent token but other users can read it (mode {:o}), run `chmod 600 {}`",
+                mode,
+                config.path().display()
+            ));
+        }
+    }
+
+    Ok(Report {
+        missing_token,
+        warnings,
+    })
+}
+
+/// Expands a path the way the agent does: `~` and environment variables, with
+/// a relative path taken from the config file's directory.
+fn expand_path(value: &str, base: &Path) -> PathBuf {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.split_once('}') {
+                Some((name, after)) => (name, after),
+                None => (braced, ""),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        expanded.push_str(&std::env::var(name).unwrap_or_default());
+        rest = after;
+    }
+    expanded.push_str(rest);
+
+    let path = match expanded.strip_prefix("~/") {
+        Some(relative) => dirs::home_dir().unwrap_or_default().join(relative),
+        None => PathBuf::from(expanded),
+    };
+    base.join(path)
+}
@@ -0,0 +1,175 @@
+use crate::config::AgentSettings;
+use anyhow::Result;
+use std::fs;
+use std::io;
+use tokio::process::Command;
+
+/// Variables the agent always gets, whatever the allowlist says, as it can't
+/// do much without them.
+const ALWAYS_ALLOWED: [&str; 2] = ["PATH", "HOME"];
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+/// Reads the user and system time of a process, plus that of its waited-for
+/// children, from `/proc/<pid>/stat`. Only available on Linux.
+pub fn cpu_seconds(pid: u32) -> Option<f64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name can contain spaces, so start after its closing paren.
+    // utime, stime, cutime and cstime are fields 14 to 17.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 2..].split_whitespace().collect();
+    let ticks: u64 = fields
+        .get(11..15)?
+        .iter()
+        .filter_map(|f| f.parse::<u64>().ok())
+        .sum();
+
+    // SAFETY: sysconf only reads a system setting.
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    (ticks_per_second > 0).then(|| ticks as f64 / ticks_per_second as f64)
+}
+
+/// Reads the resident set size of a process from `/proc/<pid>/statm`.
+pub fn resident_bytes(pid: u32) -> Option<u64> {
+    let statm = fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+
+    // SAFETY: sysconf only reads a system setting.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (page_size > 0).then(|| pages * page_size as u64)
+}
+
+/// Finds the first process whose parent is `pid`, by scanning `/proc`. Used to
+/// get from a detached `bap run` to the agent it's supervising.
+pub fn child_of(pid: u32) -> Option<u32> {
+    fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .find(|&candidate| parent_of(candidate) == Some(pid))
+}
+
+fn parent_of(pid: u32) -> Option<u32> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The parent pid is the second field after the command name
+    stat[stat.rfind(')')? + 2..]
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// Has `command` start in a session of its own, so it keeps going after the
+/// terminal it was started from is closed.
+pub fn detach(command: &mut std::process::Command) -> &mut std::process::Command {
+    // SAFETY: setsid is async-signal-safe, so it's fine to call between fork and exec.
+    unsafe {
+        std::os::unix::process::CommandExt::pre_exec(command, || {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Sets up the environment, working directory, niceness and resource limits
+/// the agent should be started with.
+pub fn apply_settings(command: &mut Command, settings: &AgentSettings) -> Result<()> {
+    if let Some(allow) = &settings.env_allow {
+        command.env_clear();
+        for (name, value) in std::env::vars_os() {
+            let Some(name_str) = name.to_str() else {
+                continue;
+            };
+            if ALWAYS_ALLOWED.contains(&name_str) || matches_any(name_str, allow) {
+                command.env(&name, value);
+            }
+        }
+    }
+    if !settings.env_deny.is_empty() {
+        for (name, _) in std::env::vars_os() {
+            if name
+                .to_str()
+                .is_some_and(|name| matches_any(name, &settings.env_deny))
+            {
+                command.env_remove(&name);
+            }
+        }
+    }
+    command.envs(&settings.env);
+
+    if let Some(dir) = &settings.working_dir {
+        if !dir.is_dir() {
+            anyhow::bail!(
+                "The agent's working directory doesn't exist: {}",
+                dir.display()
+            );
+        }
+        command.current_dir(dir);
+    }
+
+    if settings.nice.is_none() && settings.limits.is_empty() {
+        return Ok(());
+    }
+
+    // Worked out here, as nothing after the fork may fail other than the calls
+    let nice = settings.nice;
+    let open_files = settings.limits.open_files;
+    let address_space = settings.limits.address_space_bytes()?;
+    let cpu_seconds = settings.limits.cpu_seconds;
+    // SAFETY: setpriority, getrlimit and setrlimit are async-signal-safe, and
+    // the closure doesn't allocate or panic.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            set_limit(libc::RLIMIT_NOFILE, open_files)?;
+            set_limit(libc::RLIMIT_AS, address_space)?;
+            set_limit(libc::RLIMIT_CPU, cpu_seconds)?;
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Lowers a resource limit to `value`. Limits can only be raised as far as the
+/// hard limit bap itself runs with, so larger values are capped there.
+fn set_limit(resource: Resource, value: Option<u64>) -> io::Result<()> {
+    let Some(value) = value else {
+        return Ok(());
+    };
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit and setrlimit only read and write the struct given.
+    unsafe {
+        if libc::getrlimit(resource, &mut limit) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let value = (value as libc::rlim_t).min(limit.rlim_max);
+        limit.rlim_cur = value;
+        limit.rlim_max = value;
+        if libc::setrlimit(resource, &limit) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Whether an environment variable name matches one of `patterns`, where a
+/// trailing `*` matches any suffix.
+fn matches_any(name: &str, patterns: &[String]) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+}
@@ -0,0 +1,103 @@
+use crate::config::AgentSettings;
+use crate::utils::{check_name, profiles_dir};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// Settings for running an agent that don't belong to any one version, kept
+/// in `~/.bap/profiles/<name>.json` and layered over the version's config
+/// file by `bap run --profile`.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct Profile {
+    /// Tags added to the ones in the version's config, replacing any with the
+    /// same key.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugins_path: Option<PathBuf>,
+    /// The environment, limits and token to start the agent with, layered
+    /// over those in bap's config.
+    #[serde(skip_serializing_if = "AgentSettings::is_empty")]
+    pub agent: AgentSettings,
+}
+
+impl Profile {
+    pub fn load(name: &str) -> Result<Self> {
+        let path = profile_path(name)?;
+        if !path.exists() {
+            anyhow::bail!(
+                "There's no profile named {}. Create it with `bap profile create {}`",
+                name,
+                name
+            );
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read profile: {}", path.display()))?;
+        let profile: Profile = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse profile: {}", path.display()))?;
+        profile
+            .agent
+            .validate()
+            .with_context(|| format!("Invalid agent settings in profile: {}", path.display()))?;
+        Ok(profile)
+    }
+
+    pub fn save(&self, name: &str) -> Result<()> {
+        let path = profile_path(name)?;
+        fs::create_dir_all(profiles_dir())
+            .with_context(|| format!("Failed to create directory: {}", profiles_dir().display()))?;
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to write profile: {}", path.display()))
+    }
+
+    /// The `buildkite-agent start` arguments for the paths the profile sets.
+    /// Agents running side by side have build paths of their own, under the
+    /// profile's, so they can leave it out.
+    pub fn path_args(&self, with_build_path: bool) -> Vec<String> {
+        let build_path = self.build_path.as_ref().filter(|_| with_build_path);
+        [
+            ("--build-path", build_path),
+            ("--hooks-path", self.hooks_path.as_ref()),
+            ("--plugins-path", self.plugins_path.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(flag, path)| Some([flag.to_string(), path?.display().to_string()]))
+        .flatten()
+        .collect()
+    }
+}
+
+pub fn profile_path(name: &str) -> Result<PathBuf> {
+    check_name("profile", name)?;
+    Ok(profiles_dir().join(format!("{}.json", name)))
+}
+
+/// The names of every saved profile, in order.
+pub fn list() -> Result<Vec<String>> {
+    let dir = profiles_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
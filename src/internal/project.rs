@@ -0,0 +1,162 @@
+use crate::internal::profile::Profile;
+use anyhow::{Context, Result};
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The file a project checks in to say how to run its agent.
+pub const PROJECT_FILE: &str = ".bap.toml";
+
+/// A project's `.bap.toml`, read from the directory bap is run in. For example:
+///
+/// ```toml
+/// version = ">=3.70, <4"
+/// queue = "deploy"
+/// profile = "linux-docker"
+/// hooks-path = ".buildkite/agent-hooks"
+/// required-env = ["AWS_PROFILE"]
+///
+/// [tags]
+/// docker = "true"
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ProjectConfig {
+    /// An exact version, or a range such as `^3.74` to run the newest
+    /// installed version in.
+    pub version: Option<String>,
+    /// The name of a token saved with `bap auth --name`.
+    pub token: Option<String>,
+    pub profile: Option<String>,
+    pub queue: Option<String>,
+    pub tags: BTreeMap<String, String>,
+    /// Relative to the project's directory.
+    pub hooks_path: Option<PathBuf>,
+    /// Variables that have to be set for the agent to run.
+    pub required_env: Vec<String>,
+}
+
+impl ProjectConfig {
+    /// The `.bap.toml` in the current directory, if there is one.
+    pub fn load() -> Result<Option<Self>> {
+        let path = Path::new(PROJECT_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {}", PROJECT_FILE))?;
+        toml::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Failed to parse {}", PROJECT_FILE))
+    }
+
+    /// `profile` with the project's tags, queue and hooks path layered on top.
+    pub fn layer_over(&self, profile: Profile) -> Result<Profile> {
+        let mut tags = profile.tags;
+        tags.extend(
+            self.tags
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value)),
+        );
+        let hooks_path = match &self.hooks_path {
+            Some(path) => Some(
+                std::env::current_dir()
+                    .context("Failed to get the current directory")?
+                    .join(path),
+            ),
+            None => profile.hooks_path,
+        };
+
+        Ok(Profile {
+            tags,
+            queue: self.queue.clone().or(profile.queue),
+            hooks_path,
+            ..profile
+        })
+    }
+
+    /// The required variables that aren't set, either in bap's environment or
+    /// in `env`, the variables it sets for the agent.
+    pub fn missing_env(&self, env: &BTreeMap<String, String>) -> Vec<&str> {
+        self.required_env
+            .iter()
+            .filter(|name| {
+                !env.contains_key(*name) && std::env::var_os(name).is_none_or(|v| v.is_empty())
+            })
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Changes `key` in the current directory's `.bap.toml`, keeping everything
+/// else in it as it was.
+pub fn set_value(key: &str, value: &str) -> Result<()> {
+    let content = fs::read_to_string(PROJECT_FILE)
+        .with_context(|| format!("Failed to read {}", PROJECT_FILE))?;
+    let mut document: toml_edit::DocumentMut = content
+        .parse()
+        .with_context(|| format!("Failed to parse {}", PROJECT_FILE))?;
+    match document.get_mut(key).and_then(|item| item.as_value_mut()) {
+        // Keep any comment after the old value
+        Some(existing) => {
+            let decor = existing.decor().clone();
+            *existing = value.into();
+            *existing.decor_mut() = decor;
+        }
+        None => document[key] = toml_edit::value(value),
+    }
+    fs::write(PROJECT_FILE, document.to_string())
+        .with_context(|| format!("Failed to write {}", PROJECT_FILE))
+}
+
+/// Which version of the agent to run: exactly one, or the newest in a range.
+pub enum VersionSpec {
+    Exact(String),
+    Range(VersionReq),
+}
+
+impl VersionSpec {
+    /// Anything that parses as a version is taken as exactly that version, so
+    /// `3.74.1` doesn't become the range `^3.74.1`.
+    pub fn parse(value: &str) -> Self {
+        let value = value.trim();
+        let version = value.trim_start_matches('v');
+        if Version::parse(version).is_ok() {
+            return VersionSpec::Exact(version.to_string());
+        }
+        match VersionReq::parse(value) {
+            Ok(req) => VersionSpec::Range(req),
+            Err(_) => VersionSpec::Exact(value.to_string()),
+        }
+    }
+
+    /// The newest of `versions` that satisfies the spec.
+    pub fn best_match<'a>(&self, versions: impl IntoIterator<Item = &'a str>) -> Option<String> {
+        let versions = versions
+            .into_iter()
+            .map(|v| v.trim_start_matches('v'))
+            .filter_map(|v| Version::parse(v).ok());
+        match self {
+            VersionSpec::Exact(exact) => versions
+                .into_iter()
+                .any(|v| v.to_string() == *exact)
+                .then(|| exact.clone()),
+            VersionSpec::Range(req) => versions
+                .filter(|v| req.matches(v))
+                .max()
+                .map(|v| v.to_string()),
+        }
+    }
+}
+
+impl Display for VersionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionSpec::Exact(version) => write!(f, "{}", version),
+            VersionSpec::Range(req) => write!(f, "{}", req),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// An agent that stays up at least this long is considered healthy again, so
+/// the next restart starts back at the initial backoff.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RestartPolicy {
+    /// Never restart the agent
+    No,
+    /// Restart the agent when it exits with a non-zero code or is killed
+    OnFailure,
+    /// Restart the agent whenever it exits
+    Always,
+}
+
+pub enum RestartDecision {
+    /// The policy doesn't call for a restart.
+    Stop,
+    /// The agent has been restarted too often within the window.
+    GiveUp,
+    /// Restart the agent once the delay has passed.
+    After(Duration),
+}
+
+/// Decides whether and when an agent that exited without being asked to should
+/// be started again, backing off exponentially and giving up once it has
+/// restarted `max_restarts` times within `window`.
+pub struct RestartTracker {
+    policy: RestartPolicy,
+    max_restarts: usize,
+    window: Duration,
+    recent: VecDeque<Instant>,
+    backoff: Duration,
+}
+
+impl RestartTracker {
+    pub fn new(policy: RestartPolicy, max_restarts: usize, window: Duration) -> Self {
+        Self {
+            policy,
+            max_restarts,
+            window,
+            recent: VecDeque::new(),
+            backoff: INITIAL_BACKOFF,
+        }
+    }
+
+    pub fn decide(&mut self, exit_code: i32, ran_for: Duration) -> RestartDecision {
+        let wanted = match self.policy {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure => exit_code != 0,
+            RestartPolicy::Always => true,
+        };
+        if !wanted {
+            return RestartDecision::Stop;
+        }
+
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|&restart| now.duration_since(restart) > self.window)
+        {
+            self.recent.pop_front();
+        }
+
+        if self.recent.len() >= self.max_restarts {
+            return RestartDecision::GiveUp;
+        }
+
+        if ran_for >= STABLE_RUN {
+            self.backoff = INITIAL_BACKOFF;
+        }
+
+        let delay = self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        self.recent.push_back(now);
+        RestartDecision::After(delay)
+    }
+
+    /// How many restarts have happened within the current window.
+    pub fn recent_restarts(&self) -> usize {
+        self.recent.len()
+    }
+
+    pub fn max_restarts(&self) -> usize {
+        self.max_restarts
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+}
@@ -0,0 +1,275 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone, Weekday};
+
+/// A recurring window of time an agent is allowed to run in, such as
+/// `weekdays 18:00-08:00`. A window that ends before it starts runs past
+/// midnight, and its days are the days it opens on.
+#[derive(Clone, Debug)]
+pub struct Window {
+    days: [bool; 7],
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+/// The windows an agent runs in. It's running whenever any of them is open.
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+/// How far ahead to look for a window opening, which is enough to get past a
+/// week in which only one day has a window.
+const LOOKAHEAD_DAYS: i64 = 8;
+
+impl Schedule {
+    /// A schedule from the given windows, or none if there aren't any, in which
+    /// case the agent can run at any time.
+    pub fn new(windows: &[Window]) -> Option<Self> {
+        (!windows.is_empty()).then(|| Self {
+            windows: windows.to_vec(),
+        })
+    }
+
+    pub fn is_open(&self, time: DateTime<Local>) -> bool {
+        self.intervals(time)
+            .iter()
+            .any(|(start, end)| *start <= time && time < *end)
+    }
+
+    /// When the schedule next opens: `time` itself if it's already open.
+    pub fn next_open(&self, time: DateTime<Local>) -> DateTime<Local> {
+        if self.is_open(time) {
+            return time;
+        }
+        self.intervals(time)
+            .into_iter()
+            .map(|(start, _)| start)
+            .filter(|start| *start > time)
+            .min()
+            .unwrap_or(time + Duration::days(LOOKAHEAD_DAYS))
+    }
+
+    /// When the schedule next closes, following on through windows that
+    /// overlap or run into each other: `time` itself if it's already closed.
+    pub fn next_close(&self, time: DateTime<Local>) -> DateTime<Local> {
+        let intervals = self.intervals(time);
+        let mut close = time;
+        while let Some((_, end)) = intervals
+            .iter()
+            .find(|(start, end)| *start <= close && close < *end)
+        {
+            close = *end;
+        }
+        close
+    }
+
+    /// Every window's openings from the day before `time` to a week after it.
+    fn intervals(&self, time: DateTime<Local>) -> Vec<(DateTime<Local>, DateTime<Local>)> {
+        let today = time.date_naive();
+        let mut intervals = Vec::new();
+        for offset in -1..=LOOKAHEAD_DAYS {
+            let day = today + Duration::days(offset);
+            for window in &self.windows {
+                if !window.days[day.weekday().num_days_from_monday() as usize] {
+                    continue;
+                }
+                let end_day = if window.end <= window.start {
+                    day + Duration::days(1)
+                } else {
+                    day
+                };
+                // Times skipped over by a daylight saving change have no opening
+                let (Some(start), Some(end)) = (
+                    Local
+                        .from_local_datetime(&day.and_time(window.start))
+                        .earliest(),
+                    Local
+                        .from_local_datetime(&end_day.and_time(window.end))
+                        .earliest(),
+                ) else {
+                    continue;
+                };
+                intervals.push((start, end));
+            }
+        }
+        intervals
+    }
+}
+
+/// Parses a run window such as `18:00-08:00`, `weekdays 18:00-08:00`,
+/// `Sat,Sun 00:00-00:00` or `Mon-Thu 20:00-06:00`. Without days it applies to
+/// every day, and a window that starts and ends at the same time lasts all day.
+pub fn parse_window(value: &str) -> Result<Window, String> {
+    let value = value.trim();
+    let (days, times) = match value.rsplit_once(' ') {
+        Some((days, times)) => (parse_days(days.trim())?, times),
+        None => ([true; 7], value),
+    };
+
+    // Accept an en dash too, as that's how times are often written
+    let (start, end) = times
+        .split_once(['-', '–'])
+        .ok_or_else(|| format!("expected a time range such as 18:00-08:00, got {}", times))?;
+    Ok(Window {
+        days,
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+    })
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M")
+        .map_err(|_| format!("expected a time such as 18:00, got {}", value))
+}
+
+fn parse_days(value: &str) -> Result<[bool; 7], String> {
+    let mut days = [false; 7];
+    for part in value.split(',') {
+        let part = part.trim();
+        match part.to_lowercase().as_str() {
+            "daily" | "everyday" => days = [true; 7],
+            "weekdays" => days[..5].fill(true),
+            "weekends" => days[5..].fill(true),
+            _ => {
+                let (first, last) = match part.split_once('-') {
+                    Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+                    None => (parse_day(part)?, parse_day(part)?),
+                };
+                // Ranges can wrap around the end of the week, e.g. Fri-Mon
+                let mut day = first;
+                loop {
+                    days[day.num_days_from_monday() as usize] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = day.succ();
+                }
+            }
+        }
+    }
+    Ok(days)
+}
+
+fn parse_day(value: &str) -> Result<Weekday, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected a day such as Mon or weekdays, got {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A time in the first week of January 2024, which starts on a Monday and
+    /// has no daylight saving changes.
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 1, day, hour, minute, 0)
+            .single()
+            .unwrap()
+    }
+
+    fn schedule(windows: &[&str]) -> Schedule {
+        let windows: Vec<Window> = windows
+            .iter()
+            .map(|window| parse_window(window).unwrap())
+            .collect();
+        Schedule::new(&windows).unwrap()
+    }
+
+    #[test]
+    fn parses_days() {
+        let days = |value: &str| parse_window(value).unwrap().days;
+
+        assert_eq!(days("18:00-08:00"), [true; 7]);
+        assert_eq!(
+            days("weekdays 18:00-08:00"),
+            [true, true, true, true, true, false, false]
+        );
+        assert_eq!(
+            days("Sat,Sun 00:00-00:00"),
+            [false, false, false, false, false, true, true]
+        );
+        assert_eq!(
+            days("Fri-Mon 20:00–06:00"),
+            [true, false, false, false, true, true, true]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        for value in [
+            "",
+            "18:00",
+            "18:00-",
+            "25:00-08:00",
+            "18:00-8pm",
+            "Funday 18:00-08:00",
+            "Mon-Funday 18:00-08:00",
+            "weekdays",
+        ] {
+            assert!(parse_window(value).is_err(), "{:?} parsed", value);
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let schedule = schedule(&["weekdays 09:00-17:00"]);
+
+        assert!(!schedule.is_open(at(1, 8, 59)));
+        assert!(schedule.is_open(at(1, 9, 0)));
+        assert!(!schedule.is_open(at(1, 17, 0)));
+        assert_eq!(schedule.next_open(at(1, 17, 0)), at(2, 9, 0));
+        assert_eq!(schedule.next_close(at(1, 12, 0)), at(1, 17, 0));
+        // Friday evening waits for Monday
+        assert_eq!(schedule.next_open(at(5, 18, 0)), at(8, 9, 0));
+    }
+
+    #[test]
+    fn window_crossing_midnight() {
+        let schedule = schedule(&["weekdays 18:00-08:00"]);
+
+        assert!(schedule.is_open(at(1, 20, 0)));
+        assert!(schedule.is_open(at(2, 7, 59)));
+        assert!(!schedule.is_open(at(2, 8, 0)));
+        assert_eq!(schedule.next_close(at(1, 20, 0)), at(2, 8, 0));
+        assert_eq!(schedule.next_close(at(2, 3, 0)), at(2, 8, 0));
+
+        // Friday's window runs into Saturday morning, but Saturday opens none
+        assert!(schedule.is_open(at(6, 7, 0)));
+        assert!(!schedule.is_open(at(7, 7, 0)));
+        assert_eq!(schedule.next_open(at(6, 12, 0)), at(8, 18, 0));
+    }
+
+    #[test]
+    fn window_with_equal_start_and_end_lasts_all_day() {
+        let schedule = schedule(&["Sat,Sun 00:00-00:00"]);
+
+        assert!(!schedule.is_open(at(5, 23, 59)));
+        assert!(schedule.is_open(at(6, 0, 0)));
+        assert!(schedule.is_open(at(7, 23, 59)));
+        assert!(!schedule.is_open(at(8, 0, 0)));
+        assert_eq!(schedule.next_open(at(5, 10, 0)), at(6, 0, 0));
+        // Saturday runs straight into Sunday, so it stays open until Monday
+        assert_eq!(schedule.next_close(at(6, 10, 0)), at(8, 0, 0));
+    }
+
+    #[test]
+    fn windows_that_meet_close_together() {
+        let schedule = schedule(&["09:00-12:00", "12:00-17:00"]);
+
+        assert_eq!(schedule.next_close(at(3, 10, 0)), at(3, 17, 0));
+    }
+
+    #[test]
+    fn open_and_close_are_now_when_already_there() {
+        let schedule = schedule(&["09:00-17:00"]);
+
+        assert_eq!(schedule.next_open(at(3, 10, 0)), at(3, 10, 0));
+        assert_eq!(schedule.next_close(at(3, 18, 0)), at(3, 18, 0));
+    }
+
+    #[test]
+    fn no_windows_means_no_schedule() {
+        assert!(Schedule::new(&[]).is_none());
+    }
+}
@@ -0,0 +1,73 @@
+use anyhow::{Context, Result};
+use futures_util::future::select_all;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Signals that `bap run` catches and passes on to the agent rather than
+/// acting on itself.
+const FORWARDED_SIGNALS: [i32; 6] = [
+    libc::SIGINT,
+    libc::SIGTERM,
+    libc::SIGHUP,
+    libc::SIGQUIT,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+];
+
+pub struct ForwardedSignals {
+    streams: Vec<(i32, Signal)>,
+}
+
+impl ForwardedSignals {
+    pub fn new() -> Result<Self> {
+        let streams = FORWARDED_SIGNALS
+            .iter()
+            .map(|&number| {
+                signal(SignalKind::from_raw(number))
+                    .map(|stream| (number, stream))
+                    .with_context(|| format!("Failed to listen for {}", signal_name(number)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { streams })
+    }
+
+    /// Waits for the next forwarded signal and returns its number.
+    pub async fn recv(&mut self) -> i32 {
+        let pending = self.streams.iter_mut().map(|(number, stream)| {
+            Box::pin(async move {
+                stream.recv().await;
+                *number
+            })
+        });
+        select_all(pending).await.0
+    }
+}
+
+pub fn is_stop_signal(signal: i32) -> bool {
+    signal == libc::SIGINT || signal == libc::SIGTERM
+}
+
+pub fn send_signal(pid: u32, signal: i32) -> Result<()> {
+    // SAFETY: kill has no memory safety requirements, it only reports errors
+    // through its return value.
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to send {} to process {}", signal_name(signal), pid));
+    }
+    Ok(())
+}
+
+pub fn signal_name(signal: i32) -> &'static str {
+    match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGUSR2 => "SIGUSR2",
+        _ => "unknown",
+    }
+}
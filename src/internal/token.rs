@@ -0,0 +1,172 @@
+use crate::config::TokenProvider;
+use crate::internal::agent_config::AgentConfig;
+use crate::utils::{check_name, token_file, tokens_dir};
+use anyhow::{Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// The variable the agent reads its token from, which takes precedence over
+/// the token in its config file.
+pub const TOKEN_ENV_VAR: &str = "BUILDKITE_AGENT_TOKEN";
+
+/// The token the default `buildkite-agent.cfg` ships with.
+pub const PLACEHOLDER_TOKEN: &str = "xxx";
+
+/// How long a token command gets, which leaves time to unlock a password
+/// manager if it asks.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The token saved with `bap auth --global`, if there is one.
+pub fn load_global() -> Result<Option<String>> {
+    let path = token_file();
+    if !path.exists() {
+        return Ok(None);
+    }
+    read_token_file(&path).map(Some)
+}
+
+/// Saves the global token, readable by the current user alone.
+pub fn save_global(token: &str) -> Result<()> {
+    write_token_file(&token_file(), token)
+}
+
+/// Where the token called `name` is kept.
+pub fn named_token_path(name: &str) -> Result<PathBuf> {
+    check_name("token", name)?;
+    Ok(tokens_dir().join(name))
+}
+
+/// Where a named token is kept, failing if none has been saved under `name`.
+pub fn existing_named_token_path(name: &str) -> Result<PathBuf> {
+    let path = named_token_path(name)?;
+    if !path.exists() {
+        anyhow::bail!(
+            "There's no agent token named {}. Save one with `bap auth --name {}`",
+            name,
+            name
+        );
+    }
+    Ok(path)
+}
+
+pub fn load_named(name: &str) -> Result<String> {
+    read_token_file(&existing_named_token_path(name)?)
+}
+
+pub fn save_named(name: &str, token: &str) -> Result<()> {
+    let path = named_token_path(name)?;
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(tokens_dir())
+        .with_context(|| format!("Failed to create {}", tokens_dir().display()))?;
+    write_token_file(&path, token)
+}
+
+fn read_token_file(path: &Path) -> Result<String> {
+    let token = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the agent token from {}", path.display()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("The agent token in {} is empty", path.display());
+    }
+    Ok(token.to_string())
+}
+
+/// Writes a token to a file only the current user can read.
+fn write_token_file(path: &Path, token: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to write the agent token to {}", path.display()))?;
+    // The mode only applies to a new file, so tighten up one that was already there
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    writeln!(file, "{}", token)?;
+    Ok(())
+}
+
+/// Whether a config file is missing a real token, having none or the one it
+/// was installed with.
+pub fn needs_token(config: Option<&AgentConfig>) -> bool {
+    config
+        .and_then(|config| config.get("token"))
+        .is_none_or(|token| token.trim().is_empty() || token == PLACEHOLDER_TOKEN)
+}
+
+/// Whether an agent gets a token from somewhere other than its config file:
+/// a provider, bap's environment or the global token.
+pub fn supplied_elsewhere(provider: Option<&TokenProvider>) -> Result<bool> {
+    Ok(provider.is_some()
+        || std::env::var_os(TOKEN_ENV_VAR).is_some_and(|v| !v.is_empty())
+        || load_global()?.is_some())
+}
+
+/// The token to hand an agent in its environment. A configured provider
+/// always supplies it. Next comes a token bap was itself started with in
+/// `BUILDKITE_AGENT_TOKEN`, passed on explicitly so that `env_allow` and
+/// `env_deny` can't keep it from the agent. Otherwise it's the global one, but
+/// only when the agent's config file hasn't got a token of its own.
+pub async fn token_for_agent(
+    provider: Option<&TokenProvider>,
+    config: Option<&AgentConfig>,
+) -> Result<Option<String>> {
+    if let Some(provider) = provider {
+        return read_provider(provider).await.map(Some);
+    }
+    if let Some(token) = std::env::var(TOKEN_ENV_VAR).ok().filter(|t| !t.is_empty()) {
+        return Ok(Some(token));
+    }
+    if !needs_token(config) {
+        return Ok(None);
+    }
+    load_global()
+}
+
+async fn read_provider(provider: &TokenProvider) -> Result<String> {
+    let token = match provider {
+        TokenProvider::Env(name) => std::env::var(name)
+            .map_err(|_| anyhow::anyhow!("The agent token variable {} isn't set", name))?,
+        TokenProvider::File(path) => fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the agent token from {}", path.display()))?,
+        TokenProvider::Command(command) => run_command(command).await?,
+        TokenProvider::Named(name) => load_named(name)?,
+    };
+
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("The agent token provider gave an empty token");
+    }
+    Ok(token.to_string())
+}
+
+/// Runs a token command through the shell. Its standard input and error stay
+/// on the terminal, so it can ask for a password or explain why it failed.
+async fn run_command(command: &str) -> Result<String> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run the agent token command: {}", command))?;
+
+    let output = tokio::time::timeout(COMMAND_TIMEOUT, child.wait_with_output())
+        .await
+        .with_context(|| format!("The agent token command timed out: {}", command))??;
+    if !output.status.success() {
+        anyhow::bail!(
+            "The agent token command `{}` failed with {}",
+            command,
+            output.status
+        );
+    }
+    String::from_utf8(output.stdout).context("The agent token command printed invalid UTF-8")
+}
@@ -5,6 +5,8 @@ mod utils;
 
 use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(
@@ -25,8 +27,8 @@ enum Commands {
     /// List available remote Buildkite agent versions
     ListRemote,
 
-    /// Set the Buildkite agent version for the current directory
-    Use(VersionArg),
+    /// Set the Buildkite agent version or token for the current directory
+    Use(UseArgs),
 
     /// Download and install a specific Buildkite agent version
     Install(VersionArg),
@@ -35,10 +37,43 @@ enum Commands {
     Default(VersionArg),
 
     /// Run the Buildkite agent
-    Run(OptionalVersionArg),
+    Run(commands::run::RunArgs),
+
+    /// Run the Buildkite agent in the background
+    Start(commands::run::RunArgs),
+
+    /// Stop background Buildkite agents
+    Stop(StopArgs),
+
+    /// List background Buildkite agents
+    Ps,
+
+    /// Show a live dashboard of running Buildkite agents
+    Top,
+
+    /// Run the bap daemon, which manages agents over a local socket
+    Daemon(DaemonArgs),
+
+    /// Run a Buildkite agent version as a systemd (or launchd) user service
+    #[command(subcommand)]
+    Service(ServiceCommands),
 
     /// Set the authentication token for a specific Buildkite agent version
-    Auth(VersionArg),
+    Auth(AuthArgs),
+
+    /// Read and change settings in a Buildkite agent version's config file
+    #[command(subcommand)]
+    Config(ConfigCommands),
+
+    /// Manage named profiles of tags, queue, paths and token to run agents with
+    #[command(subcommand)]
+    Profile(ProfileCommands),
+
+    /// Show the captured logs for a Buildkite agent version
+    Logs(LogsArgs),
+
+    /// Show the jobs run by agents that bap supervised
+    History(HistoryArgs),
 
     /// Uninstall a specific Buildkite agent version
     Uninstall(VersionArg),
@@ -47,6 +82,105 @@ enum Commands {
     Version,
 }
 
+#[derive(Args)]
+struct DaemonArgs {
+    /// The socket to listen on, by default ~/.bap/run/bap.sock
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// Serve Prometheus metrics about the daemon's agents on this address
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Run the daemon in the background
+    #[arg(long)]
+    detach: bool,
+}
+
+#[derive(Subcommand)]
+enum ServiceCommands {
+    /// Generate and enable a service for a Buildkite agent version
+    Install(ServiceInstallArgs),
+
+    /// Disable and remove the service for a Buildkite agent version
+    Uninstall(OptionalVersionArg),
+
+    /// Show the status of the service for a Buildkite agent version
+    Status(OptionalVersionArg),
+}
+
+#[derive(Args)]
+struct ServiceInstallArgs {
+    /// The version of the Buildkite agent (optional)
+    version: Option<String>,
+
+    /// Run the agent with a profile saved with `bap profile create`
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Seconds to wait for the agent to stop when the service is stopped
+    /// before killing it, by default as long as its current job takes
+    #[arg(long, value_name = "SECONDS")]
+    grace_timeout: Option<u64>,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the value of a setting
+    Get(ConfigKeyArgs),
+
+    /// Change a setting, adding it if it isn't set yet
+    Set(ConfigSetArgs),
+
+    /// Remove a setting, so the agent's default applies
+    Unset(ConfigKeyArgs),
+
+    /// Look for problems in the config file, such as a missing token
+    Check(OptionalVersionArg),
+}
+
+#[derive(Subcommand)]
+enum ProfileCommands {
+    /// Save a profile for `bap run --profile`
+    Create(commands::profile::CreateArgs),
+
+    /// List the saved profiles
+    List,
+
+    /// Print a profile's settings
+    Show(ProfileNameArg),
+
+    /// Delete a profile
+    Delete(ProfileNameArg),
+}
+
+#[derive(Args)]
+struct ProfileNameArg {
+    /// The name of the profile
+    name: String,
+}
+
+#[derive(Args)]
+struct ConfigKeyArgs {
+    /// The version of the Buildkite agent
+    version: String,
+
+    /// The setting, such as tags, name, build-path or hooks-path
+    key: String,
+}
+
+#[derive(Args)]
+struct ConfigSetArgs {
+    /// The version of the Buildkite agent
+    version: String,
+
+    /// The setting, such as tags, name, build-path or hooks-path
+    key: String,
+
+    /// The value to set it to
+    value: String,
+}
+
 #[derive(Args)]
 struct VersionArg {
     /// The version of the Buildkite agent
@@ -59,6 +193,100 @@ struct OptionalVersionArg {
     version: Option<String>,
 }
 
+#[derive(Args)]
+struct UseArgs {
+    /// The version of the Buildkite agent
+    #[arg(required_unless_present = "token")]
+    version: Option<String>,
+
+    /// Connect agents run here with the token saved under this name
+    #[arg(long, value_name = "NAME")]
+    token: Option<String>,
+}
+
+#[derive(Args)]
+struct AuthArgs {
+    /// The version of the Buildkite agent
+    #[arg(required_unless_present_any = ["global", "all", "name"], conflicts_with = "all")]
+    version: Option<String>,
+
+    /// Save the token for every version, including ones installed later
+    #[arg(long)]
+    global: bool,
+
+    /// Write the token into the config file of every installed version
+    #[arg(long)]
+    all: bool,
+
+    /// Save the token under a name, for directories to pick with `bap use --token`
+    #[arg(long, conflicts_with_all = ["version", "global", "all"])]
+    name: Option<String>,
+
+    /// Read the token from standard input
+    #[arg(long)]
+    token_stdin: bool,
+
+    /// Read the token from a file
+    #[arg(long, value_name = "PATH", conflicts_with = "token_stdin")]
+    token_file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct LogsArgs {
+    /// The version of the Buildkite agent (optional)
+    version: Option<String>,
+
+    /// Keep printing new lines as they're written
+    #[arg(short, long)]
+    follow: bool,
+
+    /// Only show the last N lines
+    #[arg(short = 'n', long, value_name = "N")]
+    lines: Option<usize>,
+
+    /// Show the logs of the Nth agent started with `bap run --count`
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    instance: u32,
+
+    /// Read this log file instead, e.g. one passed to `bap run --log-file`
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["version", "instance"])]
+    file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct HistoryArgs {
+    /// Only show jobs run by this version of the Buildkite agent
+    #[arg(long)]
+    version: Option<String>,
+
+    /// Only show jobs finished since this long ago (e.g. 12h, 7d) or this date
+    #[arg(long, value_name = "WHEN", value_parser = commands::history::parse_since)]
+    since: Option<chrono::DateTime<chrono::Local>>,
+
+    /// Print the jobs as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct StopArgs {
+    /// The id or version of the agent to stop
+    #[arg(required_unless_present = "all")]
+    target: Option<String>,
+
+    /// Stop every background agent
+    #[arg(long)]
+    all: bool,
+
+    /// Cancel the agent's current job instead of letting it finish
+    #[arg(long)]
+    cancel: bool,
+
+    /// Seconds to wait for the agent to stop
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    timeout: u64,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     utils::ensure_bap_directories()?;
@@ -73,7 +301,7 @@ async fn main() -> Result<()> {
             commands::list_remote::run().await?;
         }
         Some(Commands::Use(args)) => {
-            commands::use_version::run(&args.version)?;
+            commands::use_version::run(args.version.as_deref(), args.token.as_deref())?;
         }
         Some(Commands::Install(args)) => {
             commands::install::run(&args.version).await?;
@@ -82,10 +310,88 @@ async fn main() -> Result<()> {
             commands::default::run(&args.version)?;
         }
         Some(Commands::Run(args)) => {
-            commands::run::run(args.version.as_deref()).await?;
+            let code = commands::run::run(args).await?;
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        Some(Commands::Start(args)) => {
+            commands::start::run(args).await?;
+        }
+        Some(Commands::Stop(args)) => {
+            commands::stop::run(args.target.as_deref(), args.all, args.cancel, args.timeout)
+                .await?;
+        }
+        Some(Commands::Ps) => {
+            commands::ps::run()?;
+        }
+        Some(Commands::Top) => {
+            commands::top::run()?;
+        }
+        Some(Commands::Daemon(args)) => {
+            commands::daemon::run(args.socket.clone(), args.metrics_addr, args.detach).await?;
+        }
+        Some(Commands::Service(ServiceCommands::Install(args))) => {
+            commands::service::install(
+                args.version.as_deref(),
+                args.profile.as_deref(),
+                args.grace_timeout,
+            )
+            .await?;
+        }
+        Some(Commands::Service(ServiceCommands::Uninstall(args))) => {
+            commands::service::uninstall(args.version.as_deref())?;
+        }
+        Some(Commands::Service(ServiceCommands::Status(args))) => {
+            commands::service::status(args.version.as_deref())?;
         }
         Some(Commands::Auth(args)) => {
-            commands::auth::run(&args.version)?;
+            let targets = commands::auth::Targets {
+                version: args.version.as_deref(),
+                global: args.global,
+                all: args.all,
+                name: args.name.as_deref(),
+            };
+            commands::auth::run(targets, args.token_stdin, args.token_file.as_deref())?;
+        }
+        Some(Commands::Config(ConfigCommands::Get(args))) => {
+            commands::config::get(&args.version, &args.key)?;
+        }
+        Some(Commands::Config(ConfigCommands::Set(args))) => {
+            commands::config::set(&args.version, &args.key, &args.value)?;
+        }
+        Some(Commands::Config(ConfigCommands::Unset(args))) => {
+            commands::config::unset(&args.version, &args.key)?;
+        }
+        Some(Commands::Config(ConfigCommands::Check(args))) => {
+            if !commands::config::check(args.version.as_deref())? {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Profile(ProfileCommands::Create(args))) => {
+            commands::profile::create(args)?;
+        }
+        Some(Commands::Profile(ProfileCommands::List)) => {
+            commands::profile::list()?;
+        }
+        Some(Commands::Profile(ProfileCommands::Show(args))) => {
+            commands::profile::show(&args.name)?;
+        }
+        Some(Commands::Profile(ProfileCommands::Delete(args))) => {
+            commands::profile::delete(&args.name)?;
+        }
+        Some(Commands::Logs(args)) => {
+            let source = match args.file.clone() {
+                Some(path) => commands::logs::Source::File(path),
+                None => commands::logs::Source::Version {
+                    version: args.version.as_deref(),
+                    instance: args.instance,
+                },
+            };
+            commands::logs::run(source, args.follow, args.lines).await?;
+        }
+        Some(Commands::History(args)) => {
+            commands::history::run(args.version.as_deref(), args.since, args.json)?;
         }
         Some(Commands::Uninstall(args)) => {
             commands::uninstall::run(&args.version)?;
@@ -3,6 +3,7 @@ use dirs::home_dir;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub fn bap_root() -> PathBuf {
     home_dir().unwrap_or_default().join(".bap")
@@ -16,6 +17,33 @@ pub fn bin_dir() -> PathBuf {
     bap_root().join("bin")
 }
 
+pub fn builds_dir() -> PathBuf {
+    bap_root().join("builds")
+}
+
+pub fn logs_dir() -> PathBuf {
+    bap_root().join("logs")
+}
+
+pub fn run_dir() -> PathBuf {
+    bap_root().join("run")
+}
+
+/// Where `bap auth --global` keeps the token shared by every version.
+pub fn token_file() -> PathBuf {
+    bap_root().join("token")
+}
+
+/// Where `bap auth --name` keeps tokens, one file per name.
+pub fn tokens_dir() -> PathBuf {
+    bap_root().join("tokens")
+}
+
+/// Where `bap profile create` keeps profiles, one JSON file per profile.
+pub fn profiles_dir() -> PathBuf {
+    bap_root().join("profiles")
+}
+
 pub fn versions_file() -> PathBuf {
     versions_dir().join("versions")
 }
@@ -56,3 +84,61 @@ fn ensure_versions_file() -> Result<()> {
     }
     Ok(())
 }
+
+/// Checks a name given to something bap keeps as a file of its own, such as a
+/// token or a profile, so it can't reach outside its directory.
+pub fn check_name(kind: &str, name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        anyhow::bail!(
+            "Invalid {} name {:?}: use letters, digits, '-', '_' and '.'",
+            kind,
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Formats a duration as its two most significant units, e.g. `2d 3h` or `4m 5s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) = (
+        secs / 86_400,
+        secs % 86_400 / 3_600,
+        secs % 3_600 / 60,
+        secs % 60,
+    );
+
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Parses a duration written as a number and a unit: `30s`, `15m`, `8h`, `7d`
+/// or `2w`.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("expected a duration such as 90m or 8h, got {}", value);
+    let (unit_at, _) = value.char_indices().last().ok_or_else(invalid)?;
+    let (amount, unit) = value.split_at(unit_at);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let seconds = amount.checked_mul(seconds).ok_or_else(invalid)?;
+    Ok(Duration::from_secs(seconds))
+}